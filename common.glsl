@@ -0,0 +1,33 @@
+// Shared by the terrain, water, sky and wireframe shaders. Included with
+// #include "common.glsl", see load_shader_file.
+
+const float pi = 3.14159;
+
+// Colors given as 0-255 components
+vec4 rgba2vec4(int r, int g, int b, float a) {
+  return vec4(r/256.0, g/256.0, b/256.0, a);
+}
+
+// Colors of the water, from the shallows out to deep water
+const vec4 shallow_water = vec4(7/256.0, 103/256.0, 163/256.0, 1.0);
+const vec4 deep_water    = vec4(3/256.0, 54/256.0, 73/256.0, 1.0);
+
+// The sun, or the moon at night, see update_time_of_day. The direction points
+// towards the light, with elevation as z.
+struct SimpleDirectionalLight {
+  vec3 color;
+  float intensity;
+  vec3 direction;
+};
+
+// Uniform blocks shared by every program, filled from CameraBlock and
+// LightBlock once per pass rather than per program.
+layout(std140) uniform Camera {
+  mat4 P; // Projection
+  mat4 V; // View
+};
+
+layout(std140) uniform Light {
+  SimpleDirectionalLight sunlight;
+  vec3 ambient;
+};
@@ -0,0 +1,16 @@
+// Fog thickening with depth and towards sea level, for fragment shaders. The
+// projection is orthographic, so depth is the window depth rather than a distance.
+
+uniform bool fog_enabled;
+uniform vec3 fog_color;
+uniform float fog_density;        // Per unit of window depth, at fog_base
+uniform float fog_height_falloff; // Per unit of elevation above fog_base
+uniform float fog_base;
+
+float fog_amount(float elevation) {
+  if (!fog_enabled)
+    return 0.0;
+
+  float density = fog_density * exp(-fog_height_falloff * max(elevation - fog_base, 0.0));
+  return 1.0 - exp(-density * gl_FragCoord.z);
+}
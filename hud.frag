@@ -0,0 +1,14 @@
+#version 150
+
+out vec4 out_color;
+
+in vec2 frag_texcoord;
+
+uniform sampler2D font;
+uniform vec4 color;
+
+void main() {
+  // The font atlas only stores coverage, in the red channel
+  float coverage = texture(font, frag_texcoord).r;
+  out_color = vec4(color.rgb, color.a * coverage);
+}
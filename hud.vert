@@ -0,0 +1,15 @@
+#version 330
+
+layout (location = 0) in vec2 position; // Pixels, origin at the top left
+layout (location = 1) in vec2 texcoord;
+
+uniform vec2 screen_size;
+
+out vec2 frag_texcoord;
+
+void main() {
+  vec2 ndc = position / screen_size * 2.0 - 1.0;
+
+  frag_texcoord = texcoord;
+  gl_Position = vec4(ndc.x, -ndc.y, 0.0, 1.0);
+}
@@ -0,0 +1,6 @@
+#version 150
+
+// Only depth is written during the shadow pass
+
+void main() {
+}
@@ -0,0 +1,10 @@
+#version 330
+
+layout (location = 0) in vec3 position;
+
+uniform mat4 light_matrix; // Light projection * light view * model
+
+void main() {
+  // Elevation is drawn towards -z, as in test.vert
+  gl_Position = light_matrix * vec4(position.xy, position.z * -1.0, 1.0);
+}
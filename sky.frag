@@ -0,0 +1,65 @@
+#version 330
+
+#include "common.glsl"
+
+in vec2 ndc;
+out vec4 out_color;
+
+// The projection is orthographic, so the sky is seen through a virtual
+// perspective camera looking the same way as the real one
+uniform mat4 inverse_view_model; // Eye space back to model space
+uniform vec2 view_extent;        // Tangent of half the field of view in x and y
+
+uniform vec3 sky_color;     // At the zenith
+uniform vec3 horizon_color;
+uniform float sun_size;     // Cosine of the angular radius of the sun disc
+uniform bool scattering_enabled;
+
+// Scattering coefficients, blue is scattered most by air and all colors alike by haze
+const vec3 rayleigh = vec3(0.058, 0.135, 0.331);
+const float mie = 0.021;
+const float mie_anisotropy = 0.76; // Haze scatters mostly forwards, around the sun
+
+// Direction of the view ray through this pixel, with elevation as z like
+// sunlight.direction
+vec3 view_ray() {
+  vec3 d = (inverse_view_model * vec4(ndc * view_extent, 1.0, 0.0)).xyz;
+  return normalize(vec3(d.xy, -d.z));
+}
+
+// Single scattering of sunlight towards the camera along the ray, with the
+// air mass growing towards the horizon
+vec3 scattering(vec3 ray, vec3 sun, vec3 light) {
+  float mu = dot(ray, sun);
+  float g = mie_anisotropy;
+
+  float rayleigh_phase = 3.0 / (16.0 * pi) * (1.0 + mu * mu);
+  float mie_phase = 3.0 / (8.0 * pi) * ((1.0 - g * g) * (1.0 + mu * mu))
+                  / ((2.0 + g * g) * pow(1.0 + g * g - 2.0 * g * mu, 1.5));
+
+  float air_mass = 1.0 / (max(ray.z, 0.0) + 0.15);
+  vec3 extinction = rayleigh + mie;
+
+  vec3 scattered = (rayleigh * rayleigh_phase + mie * mie_phase) / extinction;
+  return light * scattered * (1.0 - exp(-extinction * air_mass)) * 4.0 * pi;
+}
+
+void main() {
+  vec3 ray = view_ray();
+  vec3 sun = normalize(sunlight.direction);
+  vec3 light = sunlight.color * sunlight.intensity;
+
+  vec3 color = mix(horizon_color, sky_color, smoothstep(0.0, 0.4, ray.z));
+
+  // Fade from the gradient at night to the scattered sunlight by day
+  if (scattering_enabled)
+    color = mix(color, scattering(ray, sun, light), smoothstep(-0.1, 0.1, sun.z));
+
+  // Sun disc with a glow around it, hidden below the horizon
+  float facing = max(dot(ray, sun), 0.0);
+  float disc = smoothstep(sun_size - 0.0002, sun_size, facing);
+  float glow = pow(facing, 200.0) * 0.4;
+  color += light * (disc * 4.0 + glow) * smoothstep(-0.01, 0.01, ray.z);
+
+  out_color = vec4(color, 1.0);
+}
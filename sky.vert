@@ -0,0 +1,11 @@
+#version 330
+
+// A single triangle covering the screen, with no vertex buffer, see draw_sky
+out vec2 ndc;
+
+void main() {
+  vec2 corner = vec2((gl_VertexID << 1) & 2, gl_VertexID & 2);
+  ndc = corner * 2.0 - 1.0;
+
+  gl_Position = vec4(ndc, 0.0, 1.0);
+}
@@ -1,13 +1,11 @@
 #version 150
 
+#include "common.glsl"
+#include "fog.glsl"
+
 out vec4 out_color;
 
 uniform mat4 M; // Model
-uniform mat4 V; // View
-uniform mat4 P; // Projection
-
-uniform sampler2D sampler;
-uniform float timer;
 
 in Vertex {
   vec3 eye;
@@ -16,11 +14,77 @@ in Vertex {
   vec3 normal;
 } vs_out;
 
-uniform struct SimpleDirectionalLight {
-  vec3 color;
-  vec3 direction;
-  float intensity;
-} sunlight;
+// Cascaded shadow maps, see draw_shadow_maps. NUM_CASCADES and ENABLE_SHADOWS
+// are defined by load_shader_file.
+const float shadow_bias = 0.0005;
+
+#ifdef ENABLE_SHADOWS
+uniform sampler2DArrayShadow shadow_map;
+uniform mat4 light_matrices[NUM_CASCADES]; // World to light clip space
+uniform float cascade_splits[NUM_CASCADES]; // Far end of each cascade as window depth
+#endif
+uniform bool shadows_enabled;
+
+// Precomputed relief lighting, see compute_lightmap. Sky visibility is in red,
+// visibility of the fixed lightmap sun in green. Texture rows follow grid x.
+uniform sampler2D lightmap;
+uniform bool lightmap_enabled;
+
+// Height bands, see MATERIALS. Only the first num_materials entries are used.
+const int max_materials = MAX_MATERIALS;
+
+uniform sampler2DArray materials;
+uniform int num_materials;
+uniform int material_textures[max_materials]; // Layer of the materials array
+uniform vec3 material_colors[max_materials];  // Average color of the material
+uniform vec2 material_blends[max_materials];  // Elevations the material fades in between
+uniform float material_scales[max_materials]; // Texture repeats per grid cell
+
+// Steep slopes take the cliff material whatever their elevation
+uniform int cliff_material;
+uniform vec2 cliff_slopes;
+uniform bool triplanar_enabled;
+
+// Land use class colors per cell, transparent where unassigned. Laid out like the lightmap.
+uniform sampler2D land_use;
+uniform bool land_use_enabled;
+uniform float land_use_opacity;
+
+// Streams from the drainage analysis, see initialize_rivers_texture
+uniform sampler2D rivers;
+uniform bool rivers_enabled;
+
+// Region colors per cell with opaque borders, see regions_pixels
+uniform sampler2D regions;
+uniform bool regions_enabled;
+
+// Visible and hidden cells around the observer, see viewshed_pixels
+uniform sampler2D viewshed;
+uniform bool viewshed_enabled;
+
+// Outlines of the blocks of CHUNK_SIZE cells a chunked mesh would be split into
+uniform bool chunk_lines_enabled;
+uniform float chunk_size;
+uniform vec3 chunk_line_color;
+
+// Elevation contours, with every contour_index_every-th one thicker, see extract_contours
+uniform bool contours_enabled;
+uniform float contour_interval;
+uniform int contour_index_every;
+uniform vec3 contour_color;
+
+// Debug views replacing the shaded color, see DebugView
+uniform int debug_view;
+uniform vec2 height_range; // Lowest and highest elevation of the terrain
+uniform int lod_level;
+
+const vec3 lod_colors[4] = vec3[](vec3(0.2, 0.8, 0.2), vec3(0.9, 0.9, 0.2),
+                                  vec3(0.9, 0.5, 0.1), vec3(0.9, 0.1, 0.1));
+
+// Blue through green and yellow to red, for t from 0 to 1
+vec3 heat(float t) {
+  return clamp(vec3(1.5 - abs(4.0 * t - 3.0), 1.5 - abs(4.0 * t - 2.0), 1.5 - abs(4.0 * t - 1.0)), 0.0, 1.0);
+}
 
 // in vec3 LightDirection_cameraspace;
 // in vec3 Normal_cameraspace;
@@ -39,68 +103,174 @@ uniform struct SimpleDirectionalLight {
 //   return ( dot_product < 0.0f ) ? -face_normal : face_normal;
 // }
 
-vec4 rgba2vec4(int r, int g, int b, float a) {
-  return vec4(r/256.0, g/256.0, b/256.0, a);
+const vec4 light_specular = vec4(1.0, 1.0, 1.0, 1.0);
+
+// Texel of a material at p (grid x, y and elevation). Triplanar projection
+// blends the three axis aligned projections by the surface normal.
+vec3 material_texel(int i, vec3 p, vec3 normal, bool triplanar) {
+  float layer = float(material_textures[i]);
+  vec3 q = p * material_scales[i];
+
+  if (!triplanar)
+    return texture(materials, vec3(q.xy, layer)).rgb;
+
+  vec3 weights = pow(abs(normal), vec3(4.0));
+  weights /= weights.x + weights.y + weights.z;
+
+  return weights.x * texture(materials, vec3(q.yz, layer)).rgb
+       + weights.y * texture(materials, vec3(q.xz, layer)).rgb
+       + weights.z * texture(materials, vec3(q.xy, layer)).rgb;
 }
 
-const vec3 light_direction = vec3(0.408248, 0.816497, 0.408248);
-const vec4 light_diffuse = vec4(0.8, 0.8, 0.8, 0.0);
-const vec4 light_ambient = vec4(0.1, 0.1, 0.1, 1.0);
-const vec4 light_specular = vec4(1.0, 1.0, 1.0, 1.0);
+// The texture is scaled so it averages to the material color, and only adds detail
+vec3 material_color(int i, vec3 p, vec3 normal, bool triplanar) {
+  vec3 average = textureLod(materials, vec3(0.5, 0.5, float(material_textures[i])), 16.0).rgb;
+  return material_colors[i] * material_texel(i, p, normal, triplanar) / max(average, vec3(0.05));
+}
+
+#ifdef ENABLE_SHADOWS
+// Fraction of the sun reaching this fragment, filtered with a 3x3 PCF kernel
+float shadow_visibility(vec4 world_position) {
+  int cascade = NUM_CASCADES - 1;
+  for (int i = 0; i < NUM_CASCADES; ++i) {
+    if (gl_FragCoord.z <= cascade_splits[i]) {
+      cascade = i;
+      break;
+    }
+  }
+
+  vec4 light_position = light_matrices[cascade] * world_position;
+  vec3 coord = light_position.xyz / light_position.w * 0.5 + 0.5;
+
+  if (any(lessThan(coord, vec3(0.0))) || any(greaterThan(coord, vec3(1.0))))
+    return 1.0;
+
+  vec2 texel = 1.0 / vec2(textureSize(shadow_map, 0).xy);
+  float visibility = 0.0;
+
+  for (int x = -1; x <= 1; ++x)
+    for (int y = -1; y <= 1; ++y)
+      visibility += texture(shadow_map, vec4(coord.xy + vec2(x, y) * texel, float(cascade), coord.z - shadow_bias));
+
+  return visibility / 9.0;
+}
+#endif
 
 void main() {
 
-  vec4 color;
   float z = vs_out.position.z * -1.0;
-  float res = 256;
+  vec3 p = vec3(vs_out.position.xy, z);
+
+  // The face normal, from the screen space derivatives of the position
+  vec3 face_normal = normalize(cross(dFdx(p), dFdy(p)));
+  float slope = 1.0 - abs(face_normal.z);
+  float cliff = smoothstep(cliff_slopes.x, cliff_slopes.y, slope);
+  bool triplanar = triplanar_enabled && cliff > 0.0;
+
+  // Each band fades in over the ones below it. Bands that are fully covered
+  // by a later one are skipped rather than sampled.
+  float weights[max_materials];
+  float remaining = 1.0 - cliff;
 
-  vec4 water = rgba2vec4(7,103,163, 1);
-  vec4 shore = rgba2vec4(3,54,73,1);
-  vec4 sand  = rgba2vec4(205,179,179,1);
-  vec4 grass = rgba2vec4(0,80,9,1);
-  vec4 dirt = rgba2vec4(0,30,9,1);
+  for (int i = num_materials - 1; i >= 0; --i) {
+    float fade = i == 0 ? 1.0 : smoothstep(material_blends[i].x, material_blends[i].y, z);
+    weights[i] = remaining * fade;
+    remaining -= weights[i];
+  }
 
-  vec4 rock  = vec4(0.5, 0.5, 0.5, 1.0);
-  vec4 snow  = vec4(1.0, 1.0, 1.0, 1.0);
+  vec3 surface = vec3(0.0);
+  for (int i = 0; i < num_materials; ++i)
+    if (weights[i] > 0.001)
+      surface += weights[i] * material_color(i, p, face_normal, triplanar);
 
-  float s_water = res * 0.00;
-  float s_shore = res * 0.01;
-  float s_sand  = res * 0.045;
-  float s_grass = res * 0.09;
-  float s_dirt  = res * 0.60;
-  float s_rock  = res * 0.88;
-  float s_snow  = res * 0.99;
+  if (cliff > 0.0)
+    surface += cliff * material_color(cliff_material, p, face_normal, triplanar);
 
-  color = mix(water, shore, smoothstep(s_water, s_shore, z));
-  color = mix(color, sand,  smoothstep(s_shore, s_sand,  z));
-  color = mix(color, grass, smoothstep(s_sand,  s_grass, z));
-  color = mix(color, dirt,  smoothstep(s_grass, s_dirt,  z));
-  color = mix(color, rock,  smoothstep(s_dirt,  s_rock,  z));
-  color = mix(color, snow,  smoothstep(s_rock,  res,     z));
+  vec4 color = vec4(surface, 1.0);
 
-  vec4 tex = texture(sampler, vs_out.position.xy * vec2(512));
+  if (land_use_enabled) {
+    vec4 use = texelFetch(land_use, ivec2(round(vs_out.position.yx)), 0);
+    color.rgb = mix(color.rgb, use.rgb, use.a * land_use_opacity);
+  }
 
-  float angle = (timer / 2000) + 2000;
-  float sun_x = sin(angle) * 10 + 10;
-  float sun_z = cos(angle) * 10 + 10;
+  if (rivers_enabled) {
+    vec4 river = texture(rivers, (vs_out.position.yx + 0.5) / vec2(textureSize(rivers, 0)));
+    color.rgb = mix(color.rgb, river.rgb, river.a);
+  }
 
-  vec3 sun = (V * vec4(2.0, 2.0, 2, 0.0)).xyz;
+  if (regions_enabled) {
+    vec4 region = texelFetch(regions, ivec2(round(vs_out.position.yx)), 0);
+    color.rgb = mix(color.rgb, region.rgb, region.a);
+  }
+
+  if (viewshed_enabled) {
+    vec4 sight = texelFetch(viewshed, ivec2(round(vs_out.position.yx)), 0);
+    color.rgb = mix(color.rgb, sight.rgb, sight.a);
+  }
 
   vec3 v = (V * M * vec4(vs_out.position.xy, vs_out.position.z * -1, 0)).xyz;
-  vec3 N = (V * -1 * vec4(vs_out.normal, 0)).xyz;
+  // Elevation is z for both the normal and the sun. The normal points into the
+  // ground, like N for the water (water.frag), hence -dot(N, L) below.
+  vec3 N = (V * vec4(vs_out.normal, 0)).xyz;
 
-  vec3 L = normalize(sun - v);
+  // The sun is directional, so its direction only needs to be taken to view space
+  vec3 L = normalize((V * vec4(sunlight.direction, 0.0)).xyz);
   vec3 E = normalize(v);
   vec3 R = normalize(-reflect(L,N));
 
+  vec2 lightmap_coord = (vs_out.position.yx + 0.5) / vec2(textureSize(lightmap, 0));
+  vec2 relief = lightmap_enabled ? texture(lightmap, lightmap_coord).rg : vec2(1.0);
+
+  // Without shadow maps, e.g. on software renderers, the lightmap's sun visibility
+  // gives static shaded relief instead. It is computed for a fixed sun (see
+  // LIGHTMAP_SUN_AZIMUTH), so those shadows don't follow the day/night cycle.
+#ifdef ENABLE_SHADOWS
+  float visibility = shadows_enabled ? shadow_visibility(M * vec4(vs_out.position, 1.0)) : relief.g;
+#else
+  float visibility = relief.g;
+#endif
+  vec4 light_diffuse = vec4(sunlight.color * sunlight.intensity * visibility, 0.0);
+
   // vec4 frag_diffuse = texture2D(texture, frag_texcoord);
   vec4 diffuse_factor = max(-dot(N, L), 0.0) * light_diffuse;
-  vec4 ambient_diffuse_factor = diffuse_factor + light_ambient;
+  vec4 ambient_diffuse_factor = diffuse_factor + vec4(ambient * relief.r, 1.0);
 
-  vec4 specular_factor = pow(max(-dot(R, E), 0.0), 2.0) * light_specular;
+  vec4 specular_factor = pow(max(-dot(R, E), 0.0), 2.0) * light_specular * light_diffuse;
   specular_factor = clamp(specular_factor, 0.0, 2.0);
 
   out_color = color * (specular_factor + ambient_diffuse_factor);
+  out_color.rgb = mix(out_color.rgb, fog_color, fog_amount(z));
+
+  // The normals point into the ground, so they are negated to show the outward normal
+  if (debug_view == 1)
+    out_color = vec4(-vs_out.normal * 0.5 + 0.5, 1.0);
+  else if (debug_view == 2)
+    out_color = vec4(fract(vs_out.texcoord), 0.0, 1.0);
+  else if (debug_view == 3)
+    out_color = vec4(heat((z - height_range.x) / (height_range.y - height_range.x)), 1.0);
+  else if (debug_view == 4) {
+    // Every other chunk is darkened, since levels of detail are chosen per chunk
+    ivec2 chunk = ivec2(floor(vs_out.position.xy / chunk_size));
+    float checker = (chunk.x + chunk.y) % 2 == 0 ? 1.0 : 0.75;
+    out_color = vec4(lod_colors[min(lod_level, 3)] * checker, 1.0);
+  }
+
+  // Contours about one pixel wide, index contours two
+  if (contours_enabled) {
+    float level = z / contour_interval;
+    float width = mod(round(level), float(contour_index_every)) == 0.0 ? 1.0 : 0.5;
+    float pixels = abs(fract(level - 0.5) - 0.5) / fwidth(level);
+    float line = 1.0 - clamp(pixels - width, 0.0, 1.0);
+    out_color.rgb = mix(out_color.rgb, contour_color, line);
+  }
+
+  // Lines about two pixels wide, whatever the zoom
+  if (chunk_lines_enabled) {
+    vec2 chunk = vs_out.position.xy / chunk_size;
+    vec2 pixels = abs(fract(chunk - 0.5) - 0.5) / fwidth(chunk);
+    float line = 1.0 - clamp(min(pixels.x, pixels.y) - 1.0, 0.0, 1.0);
+    out_color.rgb = mix(out_color.rgb, chunk_line_color, line);
+  }
 
   // out_color = specular_factor * frag_specular
   //     + ambient_diffuse_factor * frag_diffuse;
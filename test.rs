@@ -7,6 +7,7 @@ extern crate glfw = "glfw-rs";
 extern crate gl;
 extern crate native;
 extern crate cgmath;
+extern crate collections;
 
 use std::cast;
 use std::ptr;
@@ -15,6 +16,10 @@ use std::mem;
 use std::vec;
 use std::io::File;
 use std::io::stdio::flush;
+use std::ascii::StrAsciiExt;
+use std::iter::range_step;
+
+use collections::priority_queue::PriorityQueue;
 
 use cgmath::quaternion::Quat;
 use cgmath::transform::Transform3D;
@@ -33,7 +38,13 @@ use gl::types::*;
 static DEBUG: bool = true;
 
 static PNG_SRC: &'static str = "heightmap2.png";
-static TEX_SRC: &'static str = "grass.png";
+
+// Bitmap font atlas covering ASCII 32-127, in rows of 16 glyphs
+static FONT_SRC: &'static str = "font.png";
+static FONT_GLYPH_WIDTH: u32 = 8;
+static FONT_GLYPH_HEIGHT: u32 = 16;
+static FONT_COLUMNS: u32 = 16;
+static FONT_ROWS: u32 = 6;
 
 static CAMERA_TRANSLATE_BY: f32 = 0.05;
 static CAMERA_SCALE_BY: f32 = 0.05;
@@ -45,15 +56,232 @@ static SCALE_MAX: f32 = 25.0;
 static SUNLIGHT_INTENSITY_MIN: f32 = 0.5;
 static SUNLIGHT_INTENSITY_MAX: f32 = 1.5;
 
+// Real seconds per simulated day while the day/night cycle is running
+static DAY_LENGTH: f32 = 120.0;
+
+// Cascaded shadow maps. Without SHADOW_MAPS the shadow code is left out of the
+// terrain shader, which then shades with the lightmap's sun visibility.
+static SHADOW_MAPS: bool = true;
+static SHADOW_MAP_SIZE: u32 = 2048;
+static NUM_CASCADES: uint = 3;
+static CASCADE_SPLIT_LAMBDA: f32 = 0.6; // 0 = uniform splits, 1 = logarithmic
+
+// Precomputed relief lightmap. The sun is given the way GIS hillshades do:
+// azimuth in degrees clockwise from north and altitude above the horizon.
+static LIGHTMAP_DIRECTIONS: uint = 16;
+static LIGHTMAP_STEPS: &'static [f32] = &[1.0, 2.0, 3.0, 4.0, 6.0, 8.0, 12.0, 16.0, 24.0, 32.0, 48.0, 64.0];
+static LIGHTMAP_SUN_AZIMUTH: f32 = 315.0;
+static LIGHTMAP_SUN_ALTITUDE: f32 = 45.0;
+static LIGHTMAP_PENUMBRA: f32 = 2.0; // Degrees over which the sun fades behind the horizon
+
+// Water surface
+static WATER_RESOLUTION: u32 = 256; // Quads along each side of the water grid
+static SEA_LEVEL_STEP: f32 = 0.5;
+
+// Terrain materials. Every texture must have the same size.
+static MATERIAL_TEXTURES: &'static [&'static str] = &["grass.png", "grass2.png"];
+static MAX_MATERIALS: uint = 8;
+
+// Height bands of the terrain, from the bottom up. Each fades in over its
+// blend range on top of the ones below. The color is the average the texture
+// is scaled to, so the texture only adds detail.
+static MATERIALS: &'static [MaterialLayer] = &[
+  MaterialLayer { texture: 1, color: Vec3 { x: 0.027, y: 0.402, z: 0.637 }, blend_start:   0.0,  blend_end:   0.0,  scale: 0.05 }, // Water
+  MaterialLayer { texture: 1, color: Vec3 { x: 0.012, y: 0.211, z: 0.285 }, blend_start:   0.0,  blend_end:   2.56, scale: 0.05 }, // Shore
+  MaterialLayer { texture: 1, color: Vec3 { x: 0.801, y: 0.699, z: 0.699 }, blend_start:   2.56, blend_end:  11.52, scale: 0.25 }, // Sand
+  MaterialLayer { texture: 0, color: Vec3 { x: 0.12,  y: 0.313, z: 0.035 }, blend_start:  11.52, blend_end:  23.04, scale: 0.25 }, // Grass
+  MaterialLayer { texture: 1, color: Vec3 { x: 0.07,  y: 0.117, z: 0.035 }, blend_start:  23.04, blend_end: 153.6,  scale: 0.25 }, // Dirt
+  MaterialLayer { texture: 1, color: Vec3 { x: 0.5,   y: 0.5,   z: 0.5   }, blend_start: 153.6,  blend_end: 225.28, scale: 0.1 },  // Rock
+  MaterialLayer { texture: 0, color: Vec3 { x: 1.0,   y: 1.0,   z: 1.0   }, blend_start: 225.28, blend_end: 256.0,  scale: 0.1 }   // Snow
+];
+
+// Slopes (1 - the vertical part of the normal) over which cliffs turn to rock
+static CLIFF_MATERIAL: uint = 5;
+static CLIFF_SLOPE_START: f32 = 0.3;
+static CLIFF_SLOPE_END: f32 = 0.55;
+
+// Land use classes painted over the terrain from a control map. Grayscale
+// maps pick the first class whose max_value is at least the pixel value, color
+// maps the class with the nearest color. Pixels at or below LAND_USE_NO_DATA,
+// or transparent ones, are left unassigned.
+static LAND_USE_CLASSES: &'static [LandUseClass] = &[
+  LandUseClass { name: "farmland",   color: [222, 196, 84],  max_value: 40 },
+  LandUseClass { name: "pasture",    color: [148, 200, 92],  max_value: 80 },
+  LandUseClass { name: "forest",     color: [34, 110, 50],   max_value: 130 },
+  LandUseClass { name: "urban",      color: [200, 64, 64],   max_value: 170 },
+  LandUseClass { name: "industrial", color: [136, 84, 170],  max_value: 200 },
+  LandUseClass { name: "desert",     color: [236, 214, 160], max_value: 230 },
+  LandUseClass { name: "barren",     color: [150, 140, 130], max_value: 255 }
+];
+static LAND_USE_NO_DATA: u8 = 2;
+static LAND_USE_OPACITY: f32 = 0.6;
+
+// Sky and fog
+static SKY_FIELD_OF_VIEW: f32 = 60.0; // Degrees, for the virtual camera the sky is seen through
+static SUN_ANGULAR_RADIUS: f32 = 1.0; // Degrees, several times the real sun so it reads at a glance
+static FOG_HEIGHT_FALLOFF: f32 = 0.03; // Fog thins by e every 1/FOG_HEIGHT_FALLOFF above sea level
+static FOG_DENSITY_STEP: f32 = 0.25;
+
+// Wireframe overlay
+static CHUNK_SIZE: u32 = 64; // Cells along each side of the outlined chunks
+static CHUNK_LINE_COLOR: Vec3<f32> = Vec3 { x: 1.0, y: 0.8, z: 0.0 };
+
+// Contour lines. Every CONTOUR_INDEX_EVERY-th contour is an index contour,
+// drawn thicker and labelled with its elevation.
+static CONTOUR_INDEX_EVERY: uint = 5;
+static CONTOUR_COLOR: Vec3<f32> = Vec3 { x: 0.36, y: 0.22, z: 0.1 };
+static CONTOUR_LABEL_MIN_POINTS: uint = 24; // Shorter index contours are left unlabelled
+static MIN_CONTOUR_INTERVAL: f32 = 0.25; // Finest interval the keys step down to
+
+// Terrain rasters, see export_rasters. Curvature is mapped from -CURVATURE_RANGE
+// to CURVATURE_RANGE, and gradients below FLAT_GRADIENT have no aspect.
+static CURVATURE_RANGE: f32 = 4.0;
+static FLAT_GRADIENT: f32 = 0.001;
+
+// Drainage analysis, see analyze_drainage. Filled depressions rise by
+// FILL_EPSILON per cell towards their outlet, so that no cell is flat.
+static FILL_EPSILON: f32 = 0.001;
+static STREAM_THRESHOLD: f32 = 200.0; // Cells draining through a cell for it to carry a stream
+static RIVER_COLOR: Vec3<f32> = Vec3 { x: 0.1, y: 0.35, z: 0.85 };
+
+// Region overlay, see Analysis::regions. Borders are drawn opaque.
+static REGION_OPACITY: f32 = 0.35;
+static REGION_BORDER_COLOR: Vec3<f32> = Vec3 { x: 0.12, y: 0.12, z: 0.12 };
+
+// Viewshed overlay, see compute_viewshed. Cells beyond the range are left as they are.
+static VIEWSHED_VISIBLE_COLOR: Vec4<f32> = Vec4 { x: 1.0, y: 0.85, z: 0.2, w: 0.4 };
+static VIEWSHED_HIDDEN_COLOR: Vec4<f32> = Vec4 { x: 0.05, y: 0.05, z: 0.2, w: 0.5 };
+
+// Neighbours of a grid point clockwise from north, i.e. -x
+static D8_OFFSETS: [(int, int), ..8] = [(-1, 0), (-1, 1), (0, 1), (1, 1), (1, 0), (1, -1), (0, -1), (-1, -1)];
+
+// Terrain statistics, see terrain_stats. Slope classes are given by their
+// upper bounds in degrees.
+static STATS_HISTOGRAM_BINS: uint = 16;
+static HYPSOMETRY_POINTS: uint = 11;
+static SLOPE_CLASSES: &'static [f32] = &[2.0, 5.0, 10.0, 15.0, 30.0, 45.0, 90.0];
+
+// Debug views
+static NORMAL_LINE_SPACING: u32 = 8; // Draw the normal of every so many vertices along each axis
+static NORMAL_LINE_LENGTH: f32 = 3.0;
+static NORMAL_LINE_COLOR: Vec4<f32> = Vec4 { x: 1.0, y: 0.2, z: 0.8, w: 1.0 };
+
+// Water reflection and refraction
+static WATER_TARGET_DIVISOR: u32 = 2; // Targets are rendered at a fraction of the window size
+static CLIP_PLANE_OFFSET: f32 = 0.1; // Overlap at the water plane, hiding seams along the shore
+static RIPPLE_MAP_SIZE: u32 = 256;
+
+// Ripples as (cycles across x, cycles across y, phase). Whole numbers of
+// cycles keep the ripple map tileable.
+static RIPPLES: &'static [(f32, f32, f32)] = &[
+  ( 3.0,  1.0, 0.0), (-2.0,  5.0, 1.3), ( 7.0, -4.0, 2.1), ( 1.0, -9.0, 4.0),
+  (-11.0, 6.0, 0.7), ( 13.0, 9.0, 5.2), (-5.0, -14.0, 3.3), ( 17.0, -3.0, 1.9)
+];
+
+// Sum of Gerstner waves moving the water surface. Speeds are in grid units per frame.
+static WAVES: &'static [GerstnerWave] = &[
+  GerstnerWave { direction: Vec2 { x:  0.3, y:  0.2 }, wavelength: 5.0, amplitude: 0.25, steepness: 0.5, speed: 0.02 },
+  GerstnerWave { direction: Vec2 { x: -0.2, y:  0.5 }, wavelength: 3.1, amplitude: 0.12, steepness: 0.4, speed: 0.015 },
+  GerstnerWave { direction: Vec2 { x:  0.6, y: -0.1 }, wavelength: 1.7, amplitude: 0.06, steepness: 0.3, speed: 0.012 },
+  GerstnerWave { direction: Vec2 { x: -0.4, y: -0.3 }, wavelength: 9.0, amplitude: 0.3,  steepness: 0.6, speed: 0.03 }
+];
+
+static WINDOW_WIDTH: u32 = 1920;
+static WINDOW_HEIGHT: u32 = 1280;
+
+// Frame rate used when playing back a flythrough in headless mode
+static FLYTHROUGH_FPS: f32 = 30.0;
+
 // Shader sources
 static VS_SRC: &'static str = "test.vert";
 static FS_SRC: &'static str = "test.frag";
-static GS_SRC: &'static str = "test.geom";
+static GS_SRC: &'static str = "test.geom"; // Optional
+static HUD_VS_SRC: &'static str = "hud.vert";
+static HUD_FS_SRC: &'static str = "hud.frag";
+static SHADOW_VS_SRC: &'static str = "shadow.vert";
+static SHADOW_FS_SRC: &'static str = "shadow.frag";
+static WATER_VS_SRC: &'static str = "water.vert";
+static WATER_FS_SRC: &'static str = "water.frag";
+static SKY_VS_SRC: &'static str = "sky.vert";
+static SKY_FS_SRC: &'static str = "sky.frag";
+static WIREFRAME_VS_SRC: &'static str = "wireframe.vert";
+static WIREFRAME_FS_SRC: &'static str = "wireframe.frag";
+
+// Deepest nesting of #include allowed in shaders
+static MAX_INCLUDE_DEPTH: uint = 8;
+
+// Binding points of the uniform blocks in common.glsl
+static CAMERA_BLOCK_BINDING: GLuint = 0;
+static LIGHT_BLOCK_BINDING: GLuint = 1;
 
 // Globals  -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- --
 
 static mut ticks: f32 = 0.0;
-static mut draw_loops: bool = false;
+
+// Mesh edges drawn over the shaded terrain, see draw_wireframe
+static mut wireframe_enabled: bool = false;
+static mut wireframe_color: Vec4<f32> = Vec4 { x: 0.0, y: 0.0, z: 0.0, w: 0.6 };
+static mut wireframe_width: f32 = 1.0;
+static mut chunk_lines_enabled: bool = false;
+
+// Elevation contours over the terrain, see extract_contours
+static mut contours_enabled: bool = false;
+static mut contour_interval: f32 = 10.0;
+
+// Streams from the drainage analysis painted over the terrain
+static mut rivers_enabled: bool = false;
+
+// Watershed basins, or Voronoi regions if seeds were given, painted over the terrain
+static mut regions_enabled: bool = false;
+
+// Line of sight from an observer placed on the cell under the cursor
+static mut viewshed_enabled: bool = false;
+static mut observer_height: f32 = 2.0; // Eye height above the ground
+static mut view_range: f32 = 250.0;    // Cells
+static mut observer_request: Option<(u32, u32)> = None; // Cell to compute the viewshed from next frame
+
+static mut debug_view: DebugView = NoDebugView;
+static mut normal_lines_enabled: bool = false;
+
+// Light each triangle with its face normal, see test.geom. Unavailable when
+// the terrain program was linked without a geometry stage.
+static mut flat_shading: bool = false;
+static mut flat_shading_available: bool = false;
+
+// Hour of the day (0-24) driving the sun when the day/night cycle is enabled,
+// and the simulated day it belongs to
+static mut time_of_day: f32 = 12.0;
+static mut simulation_day: int = 1;
+static mut day_cycle: bool = false;
+
+static mut shadows_enabled: bool = true;
+static mut lightmap_enabled: bool = true;
+
+// Elevation of the water surface, adjustable at runtime between the lowest and
+// highest terrain heights
+static mut sea_level: f32 = 5.0;
+static mut sea_level_range: (f32, f32) = (0.0, 0.0);
+static mut reflections_enabled: bool = true;
+static mut framebuffer_resize: Option<(i32, i32)> = None; // New size to fit the water targets to next frame
+
+// Project textures along all three axes on cliffs, so they are not stretched
+static mut triplanar_enabled: bool = true;
+
+// Paint the land use map over the terrain, if one was given
+static mut land_use_enabled: bool = true;
+
+// Fog over the terrain, in the horizon color unless fog_color is set
+static mut fog_enabled: bool = true;
+static mut fog_density: f32 = 1.5;
+static mut fog_color: Option<Vec3<f32>> = None;
+
+// Rayleigh and Mie scattering in the sky instead of a plain gradient
+static mut scattering_enabled: bool = false;
+
+// Last known cursor position in window coordinates and what lies beneath it
+static mut cursor_pos: (f64, f64) = (0.0, 0.0);
+static mut cursor_moved: bool = false;
+static mut cursor_pick: Option<Pick> = None;
 
 static mut world: World = World {
   model_matrix:      Mat4 {
@@ -71,7 +299,10 @@ static mut world: World = World {
     color:     Vec3 { x:  0.8, y:  1.0, z:  1.0 },
     direction: Vec3 { x:  0.2, y:  0.2, z:  0.2 },
     intensity: 0.5
-  }
+  },
+
+  ambient:   Vec3 { x: 0.1, y: 0.1, z: 0.1 },
+  sky_color: Vec3 { x: 34.0/256.0, y: 37.0/256.0, z: 39.0/256.0 }
 };
 
 static mut camera: Camera = Camera {
@@ -97,20 +328,46 @@ static mut screen: Screen = Screen {
 };
 
 static mut vs_data: VertexShaderData = VertexShaderData {
-  projection_matrix: 0,
-  view_matrix: 0,
-  model_matrix: 0,
-  rotation: 0,
-  scale: 0,
-  translation: 0,
-  ticks: 0
+  model_matrix: Uniform { location: -1 },
+  clip_plane: Uniform { location: -1 },
+  flat_shading: Uniform { location: -1 }
 };
 
 static mut fs_data: FragmentShaderData = FragmentShaderData {
-  sunlight: 0,
-  sunlight_color: 0,
-  sunlight_direction: 0,
-  sunlight_intensity: 0
+  shadow_map: Uniform { location: -1 },
+  shadows_enabled: Uniform { location: -1 },
+  light_matrices: Uniform { location: -1 },
+  cascade_splits: Uniform { location: -1 },
+  lightmap: Uniform { location: -1 },
+  lightmap_enabled: Uniform { location: -1 },
+  land_use: Uniform { location: -1 },
+  land_use_enabled: Uniform { location: -1 },
+  land_use_opacity: Uniform { location: -1 },
+  rivers: Uniform { location: -1 },
+  rivers_enabled: Uniform { location: -1 },
+  regions: Uniform { location: -1 },
+  regions_enabled: Uniform { location: -1 },
+  viewshed: Uniform { location: -1 },
+  viewshed_enabled: Uniform { location: -1 },
+  fog: FogUniforms {
+    enabled:        Uniform { location: -1 },
+    color:          Uniform { location: -1 },
+    density:        Uniform { location: -1 },
+    height_falloff: Uniform { location: -1 },
+    base:           Uniform { location: -1 }
+  },
+  chunk_lines_enabled: Uniform { location: -1 },
+  chunk_size: Uniform { location: -1 },
+  chunk_line_color: Uniform { location: -1 },
+  contours_enabled: Uniform { location: -1 },
+  contour_interval: Uniform { location: -1 },
+  contour_index_every: Uniform { location: -1 },
+  contour_color: Uniform { location: -1 },
+  debug_view: Uniform { location: -1 },
+  height_range: Uniform { location: -1 },
+  lod_level: Uniform { location: -1 },
+  materials: Uniform { location: -1 },
+  triplanar_enabled: Uniform { location: -1 }
 };
 
 // -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- --
@@ -122,7 +379,10 @@ struct World {
   scale:         f32,
   translation:   Vec3<f32>,
 
-  sunlight: DirectionalLight
+  sunlight: DirectionalLight,
+
+  ambient:   Vec3<GLfloat>,
+  sky_color: Vec3<GLfloat>
 }
 
 struct Camera {
@@ -137,21 +397,51 @@ struct Screen {
   projection_matrix: Mat4<f32>,
 }
 
+// The camera and the sun are in the Camera and Light uniform blocks
 struct VertexShaderData {
-  projection_matrix: i32,
-  view_matrix: i32,
-  model_matrix: i32,
-  rotation: i32,
-  scale: i32,
-  translation: i32,
-  ticks: i32
+  model_matrix: Uniform<Mat4<f32>>,
+  clip_plane: Uniform<Vec4<f32>>,
+  flat_shading: Uniform<bool>
 }
 
 struct FragmentShaderData {
-  sunlight: i32,
-  sunlight_color: i32,
-  sunlight_direction: i32,
-  sunlight_intensity: i32
+  shadow_map: Uniform<i32>,
+  shadows_enabled: Uniform<bool>,
+  light_matrices: Uniform<Mat4<f32>>,
+  cascade_splits: Uniform<f32>,
+  lightmap: Uniform<i32>,
+  lightmap_enabled: Uniform<bool>,
+  land_use: Uniform<i32>,
+  land_use_enabled: Uniform<bool>,
+  land_use_opacity: Uniform<f32>,
+  rivers: Uniform<i32>,
+  rivers_enabled: Uniform<bool>,
+  regions: Uniform<i32>,
+  regions_enabled: Uniform<bool>,
+  viewshed: Uniform<i32>,
+  viewshed_enabled: Uniform<bool>,
+  fog: FogUniforms,
+  chunk_lines_enabled: Uniform<bool>,
+  chunk_size: Uniform<f32>,
+  chunk_line_color: Uniform<Vec3<f32>>,
+  contours_enabled: Uniform<bool>,
+  contour_interval: Uniform<f32>,
+  contour_index_every: Uniform<i32>,
+  contour_color: Uniform<Vec3<f32>>,
+  debug_view: Uniform<i32>,
+  height_range: Uniform<Vec2<f32>>,
+  lod_level: Uniform<i32>,
+  materials: Uniform<i32>,
+  triplanar_enabled: Uniform<bool>
+}
+
+// The uniforms of fog.glsl, in the terrain and water programs
+struct FogUniforms {
+  enabled:        Uniform<bool>,
+  color:          Uniform<Vec3<f32>>,
+  density:        Uniform<f32>,
+  height_falloff: Uniform<f32>,
+  base:           Uniform<f32>
 }
 
 struct DirectionalLight {
@@ -160,12 +450,221 @@ struct DirectionalLight {
   intensity: GLfloat
 }
 
+// Uniforms  -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- --
+
+// An active uniform of a linked program, as listed by GetActiveUniform
+struct ActiveUniform {
+  name:     ~str,   // Arrays without the [0] GL appends
+  location: GLint,
+  kind:     GLenum  // e.g. gl::FLOAT_VEC3, whether or not it is an array
+}
+
+// The active uniforms of a program. Looking a uniform up warns when the program
+// has no such uniform, or one of another type, rather than silently storing -1.
+// Uniforms in blocks have no location and are left to the uniform buffers.
+struct UniformTable {
+  label:    ~str, // Names the program in warnings, e.g. "water"
+  uniforms: ~[ActiveUniform]
+}
+
+// Location of a uniform checked against T when looked up. Missing or mismatched
+// uniforms are given location -1, which GL ignores.
+struct Uniform<T> {
+  location: GLint
+}
+
+// Rust types a uniform can be set from, and the GLSL types they match
+trait UniformValue {
+  fn accepts(_: Option<Self>, kind: GLenum) -> bool;
+  unsafe fn set_uniform(location: GLint, values: &[Self]);
+}
+
+impl<T: UniformValue> Uniform<T> {
+  unsafe fn set(&self, value: T) {
+    UniformValue::set_uniform(self.location, &[value]);
+  }
+
+  unsafe fn set_array(&self, values: &[T]) {
+    UniformValue::set_uniform(self.location, values);
+  }
+}
+
+fn is_sampler(kind: GLenum) -> bool {
+  match kind {
+    gl::SAMPLER_2D | gl::SAMPLER_3D | gl::SAMPLER_CUBE | gl::SAMPLER_2D_SHADOW |
+    gl::SAMPLER_2D_ARRAY | gl::SAMPLER_2D_ARRAY_SHADOW => true,
+    _ => false
+  }
+}
+
+impl UniformValue for f32 {
+  fn accepts(_: Option<f32>, kind: GLenum) -> bool { kind == gl::FLOAT }
+  unsafe fn set_uniform(location: GLint, values: &[f32]) {
+    gl::Uniform1fv(location, values.len() as GLint, values.as_ptr());
+  }
+}
+
+// Integers also set samplers, to a texture unit
+impl UniformValue for i32 {
+  fn accepts(_: Option<i32>, kind: GLenum) -> bool { kind == gl::INT || is_sampler(kind) }
+  unsafe fn set_uniform(location: GLint, values: &[i32]) {
+    gl::Uniform1iv(location, values.len() as GLint, values.as_ptr());
+  }
+}
+
+impl UniformValue for bool {
+  fn accepts(_: Option<bool>, kind: GLenum) -> bool { kind == gl::BOOL }
+  unsafe fn set_uniform(location: GLint, values: &[bool]) {
+    let values: ~[GLint] = values.iter().map(|&v| v as GLint).collect();
+    gl::Uniform1iv(location, values.len() as GLint, values.as_ptr());
+  }
+}
+
+impl UniformValue for Vec2<f32> {
+  fn accepts(_: Option<Vec2<f32>>, kind: GLenum) -> bool { kind == gl::FLOAT_VEC2 }
+  unsafe fn set_uniform(location: GLint, values: &[Vec2<f32>]) {
+    gl::Uniform2fv(location, values.len() as GLint, values.as_ptr() as *GLfloat);
+  }
+}
+
+impl UniformValue for Vec3<f32> {
+  fn accepts(_: Option<Vec3<f32>>, kind: GLenum) -> bool { kind == gl::FLOAT_VEC3 }
+  unsafe fn set_uniform(location: GLint, values: &[Vec3<f32>]) {
+    gl::Uniform3fv(location, values.len() as GLint, values.as_ptr() as *GLfloat);
+  }
+}
+
+impl UniformValue for Vec4<f32> {
+  fn accepts(_: Option<Vec4<f32>>, kind: GLenum) -> bool { kind == gl::FLOAT_VEC4 }
+  unsafe fn set_uniform(location: GLint, values: &[Vec4<f32>]) {
+    gl::Uniform4fv(location, values.len() as GLint, values.as_ptr() as *GLfloat);
+  }
+}
+
+impl UniformValue for Mat4<f32> {
+  fn accepts(_: Option<Mat4<f32>>, kind: GLenum) -> bool { kind == gl::FLOAT_MAT4 }
+  unsafe fn set_uniform(location: GLint, values: &[Mat4<f32>]) {
+    gl::UniformMatrix4fv(location, values.len() as GLint, gl::FALSE, values.as_ptr() as *GLfloat);
+  }
+}
+
+impl UniformTable {
+  unsafe fn reflect(program: GLuint, label: &str) -> UniformTable {
+    let mut num_active = 0;
+    gl::GetProgramiv(program, gl::ACTIVE_UNIFORMS, &mut num_active);
+
+    let mut uniforms = ~[];
+    for i in range(0, num_active as GLuint) {
+      let mut name_buffer = vec::from_elem(256, 0u8);
+      let mut length = 0;
+      let mut size = 0;
+      let mut kind = 0;
+      gl::GetActiveUniform(program, i, name_buffer.len() as GLint, &mut length, &mut size, &mut kind,
+                           name_buffer.as_mut_ptr() as *mut GLchar);
+      let name = str::from_utf8(name_buffer.slice(0, length as uint)).unwrap_or("?");
+
+      let location = name.with_c_str(|ptr| gl::GetUniformLocation(program, ptr));
+      if location < 0 { continue }
+
+      let name = if name.ends_with("[0]") { name.slice_to(name.len() - 3) } else { name };
+      uniforms.push(ActiveUniform { name: name.to_owned(), location: location, kind: kind });
+    }
+
+    UniformTable { label: label.to_owned(), uniforms: uniforms }
+  }
+
+  fn find<'a>(&'a self, name: &str) -> Option<&'a ActiveUniform> {
+    let found = self.uniforms.iter().find(|u| u.name.as_slice() == name);
+    if found.is_none() {
+      println!("Warning: the {} program has no active uniform {}", self.label, name);
+    }
+    found
+  }
+
+  fn uniform<T: UniformValue>(&self, name: &str) -> Uniform<T> {
+    match self.find(name) {
+      Some(u) if UniformValue::accepts(None::<T>, u.kind) => Uniform { location: u.location },
+      Some(u) => {
+        println!("Warning: the {} program declares uniform {} as GL type 0x{:x}, which is set from another type",
+                 self.label, name, u.kind);
+        Uniform { location: -1 }
+      }
+      None => Uniform { location: -1 }
+    }
+  }
+}
+
+// std140 layouts of the Camera and Light uniform blocks in common.glsl
+struct CameraBlock {
+  projection: Mat4<f32>,
+  view:       Mat4<f32>
+}
+
+struct LightBlock {
+  sun_color:     Vec3<f32>,
+  sun_intensity: f32,
+  sun_direction: Vec3<f32>,
+  padding:       f32,       // Each vec3 starts on 16 bytes
+  ambient:       Vec3<f32>,
+  padding2:      f32
+}
+
+// Vertex layouts  -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- --
+
+// One attribute of a vertex type, as handed to VertexAttribPointer
+struct VertexAttribute {
+  name:       &'static str, // Name of the input in the vertex shader
+  components: GLint,        // Number of GLfloats
+  offset:     uint          // Bytes from the start of the vertex
+}
+
+// Vertex types list their attributes, so vertex arrays can be set up from the
+// description, see initialize_vertex_layout
+trait VertexFormat {
+  fn attributes(_: Option<Self>) -> ~[VertexAttribute];
+}
+
+// Describes a field of a vertex struct made up of GLfloats, e.g. a Vec3<GLfloat>,
+// taking its offset and number of components from the struct itself
+macro_rules! vertex_attribute(
+  ($vertex:ty, $field:ident, $name:expr) => (unsafe {
+    let vertex: $vertex = mem::uninit();
+    let base: uint = cast::transmute(&vertex);
+    let field: uint = cast::transmute(&vertex.$field);
+    let size = mem::size_of_val(&vertex.$field);
+    cast::forget(vertex);
+
+    VertexAttribute { name: $name, components: (size / mem::size_of::<GLfloat>()) as GLint, offset: field - base }
+  })
+)
+
 struct Vertex {
   position: Vec3<GLfloat>,
   normal:   Vec3<GLfloat>,
   texture:  Vec2<GLfloat>
 }
 
+impl VertexFormat for Vertex {
+  fn attributes(_: Option<Vertex>) -> ~[VertexAttribute] {
+    ~[vertex_attribute!(Vertex, position, "position"),
+      vertex_attribute!(Vertex, normal,   "normal"),
+      vertex_attribute!(Vertex, texture,  "texcoord")]
+  }
+}
+
+// Bare positions, as used by the water grid and the normal lines
+impl VertexFormat for Vec2<GLfloat> {
+  fn attributes(_: Option<Vec2<GLfloat>>) -> ~[VertexAttribute] {
+    ~[VertexAttribute { name: "position", components: 2, offset: 0 }]
+  }
+}
+
+impl VertexFormat for Vec3<GLfloat> {
+  fn attributes(_: Option<Vec3<GLfloat>>) -> ~[VertexAttribute] {
+    ~[VertexAttribute { name: "position", components: 3, offset: 0 }]
+  }
+}
+
 impl Vertex {
   pub fn new(
     vx: f32, vy: f32, vz: f32,
@@ -179,6 +678,413 @@ impl Vertex {
   }
 }
 
+// Filtered terrain heights, indexed the same way as the vertices
+struct HeightGrid {
+  width:   u32,
+  height:  u32,
+  heights: ~[f32]
+}
+
+impl HeightGrid {
+  pub fn get(&self, x: u32, y: u32) -> f32 {
+    self.heights[x * self.width + y]
+  }
+
+  // Vertex position in model space, where the shaders flip elevation to -z
+  pub fn position(&self, x: u32, y: u32) -> Vec3<f32> {
+    Vec3::new(x as f32, y as f32, -self.get(x, y))
+  }
+
+  pub fn contains(&self, x: f32, y: f32) -> bool {
+    x >= 0.0 && y >= 0.0 && x <= (self.width - 1) as f32 && y <= (self.height - 1) as f32
+  }
+
+  // Bilinearly interpolated height between grid points
+  pub fn sample(&self, x: f32, y: f32) -> f32 {
+    let x0 = (x.floor() as u32).min(self.width - 2);
+    let y0 = (y.floor() as u32).min(self.height - 2);
+    let fx = x - x0 as f32;
+    let fy = y - y0 as f32;
+
+    let top = self.get(x0, y0) * (1.0 - fy) + self.get(x0, y0 + 1) * fy;
+    let bottom = self.get(x0 + 1, y0) * (1.0 - fy) + self.get(x0 + 1, y0 + 1) * fy;
+    top * (1.0 - fx) + bottom * fx
+  }
+
+  // Height at a grid point, with points off the grid taking the nearest edge
+  pub fn get_clamped(&self, x: int, y: int) -> f32 {
+    self.get(x.max(0).min(self.width as int - 1) as u32, y.max(0).min(self.height as int - 1) as u32)
+  }
+
+  // Elevation change per cell along grid x and y, from Horn's weighted
+  // differences over the surrounding 3x3 points
+  pub fn gradient(&self, x: u32, y: u32) -> (f32, f32) {
+    let z = |dx: int, dy: int| self.get_clamped(x as int + dx, y as int + dy);
+    let dzdx = (z(1, -1) + 2.0 * z(1, 0) + z(1, 1) - z(-1, -1) - 2.0 * z(-1, 0) - z(-1, 1)) / 8.0;
+    let dzdy = (z(-1, 1) + 2.0 * z(0, 1) + z(1, 1) - z(-1, -1) - 2.0 * z(0, -1) - z(1, -1)) / 8.0;
+    (dzdx, dzdy)
+  }
+
+  // Slope in degrees from the horizontal
+  pub fn slope(&self, x: u32, y: u32) -> f32 {
+    let (dzdx, dzdy) = self.gradient(x, y);
+    (dzdx * dzdx + dzdy * dzdy).sqrt().atan().to_degrees()
+  }
+
+  // Negated Laplacian of the heights, positive on hilltops and ridges the way
+  // GIS curvature is
+  pub fn curvature(&self, x: u32, y: u32) -> f32 {
+    let z = |dx: int, dy: int| self.get_clamped(x as int + dx, y as int + dy);
+    4.0 * z(0, 0) - z(1, 0) - z(-1, 0) - z(0, 1) - z(0, -1)
+  }
+}
+
+// Terrain point hit by a ray cast from the cursor
+struct Pick {
+  cell:      (u32, u32),
+  position:  Vec3<f32>, // Model space
+  world:     Vec3<f32>, // After applying the model matrix
+  elevation: f32
+}
+
+// GL objects needed to draw the terrain
+struct Scene {
+  program:         GLuint,
+  vertex_array_id: GLuint,
+  num_indices:     uint,
+
+  // Model space bounding box of the terrain
+  terrain_min: Vec3<f32>,
+  terrain_max: Vec3<f32>,
+
+  shadow_map: ShadowMap,
+
+  // Uniform buffers behind the Camera and Light blocks
+  camera_buffer_id: GLuint,
+  light_buffer_id:  GLuint,
+
+  lightmap_texture_id: GLuint,
+  height_texture_id:   GLuint,
+  material_texture_id: GLuint,
+  land_use_texture_id: GLuint, // 0 without a land use map
+  rivers_texture_id:   GLuint,
+  regions_texture_id:  GLuint,
+  viewshed_texture_id: GLuint, // Transparent until an observer is placed
+
+  sky:       Sky,
+  water:     Water,
+  wireframe: Wireframe,
+  normal_lines: NormalLines
+}
+
+// What the terrain is colored by instead of its shading. Must match debug_view in test.frag.
+#[deriving(Eq)]
+enum DebugView {
+  NoDebugView,
+  NormalView,
+  TexcoordView,
+  HeightView,
+  LodView
+}
+
+// A line along the normal of every NORMAL_LINE_SPACING-th vertex, drawn with the wireframe program
+struct NormalLines {
+  vertex_array_id:  GLuint,
+  vertex_buffer_id: GLuint,
+  num_vertices:     uint
+}
+
+// Flat colored program the terrain edges are drawn with
+struct Wireframe {
+  program: GLuint,
+
+  model_matrix:      Uniform<Mat4<f32>>,
+  color:             Uniform<Vec4<f32>>
+}
+
+// Full screen sky gradient and sun disc, drawn before the terrain
+struct Sky {
+  program:         GLuint,
+  vertex_array_id: GLuint, // Empty, the vertices are generated in sky.vert
+
+  inverse_view_model: Uniform<Mat4<f32>>,
+  view_extent:        Uniform<Vec2<f32>>,
+  sky_color:          Uniform<Vec3<f32>>,
+  horizon_color:      Uniform<Vec3<f32>>,
+  sun_size:           Uniform<f32>,
+  scattering_enabled: Uniform<bool>
+}
+
+struct LandUseClass {
+  name:      &'static str,
+  color:     [u8, ..3],
+  max_value: u8
+}
+
+struct MaterialLayer {
+  texture:     uint, // Index into MATERIAL_TEXTURES
+  color:       Vec3<f32>,
+  blend_start: f32,  // Elevations the layer fades in between
+  blend_end:   f32,
+  scale:       f32   // Texture repeats per grid cell
+}
+
+struct GerstnerWave {
+  direction:  Vec2<f32>,
+  wavelength: f32,
+  amplitude:  f32,
+  steepness:  f32, // 0 gives sine waves, 1 the sharpest crests
+  speed:      f32
+}
+
+// Water surface mesh and its shader program, drawn after the terrain
+struct Water {
+  program:          GLuint,
+  vertex_array_id:  GLuint,
+  vertex_buffer_id: GLuint,
+  index_buffer_id:  GLuint,
+  num_indices:      uint,
+
+  reflection:              WaterTarget,
+  refraction:              WaterTarget,
+  ripple_map_texture_id:   GLuint,
+
+  model_matrix:       Uniform<Mat4<f32>>,
+  timer:              Uniform<f32>,
+  sea_level:          Uniform<f32>,
+  height_map:         Uniform<i32>,
+
+  reflections_enabled: Uniform<bool>,
+  reflection_map:      Uniform<i32>,
+  refraction_map:      Uniform<i32>,
+  normal_map:          Uniform<i32>,
+
+  fog: FogUniforms,
+
+  wave_directions: Uniform<Vec2<f32>>,
+  wave_lengths:    Uniform<f32>,
+  wave_amplitudes: Uniform<f32>,
+  wave_steepness:  Uniform<f32>,
+  wave_speeds:     Uniform<f32>
+}
+
+// Color texture and depth buffer the water samples its reflection or refraction from
+struct WaterTarget {
+  framebuffer_id:   GLuint,
+  color_texture_id: GLuint,
+  depth_buffer_id:  GLuint,
+  width:            u32,
+  height:           u32
+}
+
+// Depth texture array holding one shadow map per cascade, rendered from the sun
+struct ShadowMap {
+  program:          GLuint,
+  framebuffer_id:   GLuint,
+  depth_texture_id: GLuint,
+
+  light_matrix: Uniform<Mat4<f32>>
+}
+
+// On-screen overlay contents. Window events are reported in the status line
+// rather than on stdout, so the viewer can run next to the simulation's logs.
+struct Hud {
+  visible:   bool,
+  show_help: bool,
+  fps:       u64,
+  frame_ms:  f64,
+  status:    ~str,
+  help:      ~str, // Current key bindings, see bindings_help
+  land_use:  ~[uint], // Cells per land use class for the legend, empty without a map
+  contour_labels: ~[ContourLabel],
+  labelled_interval: Option<f32> // Interval of contour_labels, None until contours are first shown
+}
+
+// A contour line through grid x, y. Closed lines end on their first point.
+struct Contour {
+  elevation: f32,
+  index:     bool, // Every CONTOUR_INDEX_EVERY-th contour
+  points:    ~[Vec2<f32>]
+}
+
+// An index contour's elevation, shown at a point on it in model space
+struct ContourLabel {
+  position: Vec3<f32>,
+  text:     ~str
+}
+
+// How flow leaving a cell is routed: all of it to the steepest of the eight
+// neighbours, or split between two along the steepest downslope direction
+// (Tarboton's D-infinity)
+enum FlowMethod {
+  D8,
+  DInfinity
+}
+
+// Results of analyze_drainage, per cell in grid order
+struct Drainage {
+  filled:       ~[f32],          // Heights with every depression filled to its spill point
+  order:        ~[uint],         // Cells from lowest to highest filled height
+  downstream:   ~[Option<uint>], // D8 flow direction as the receiving cell, None at outlets
+  accumulation: ~[f32],          // Cells draining through each cell, itself included
+  basins:       ~[uint],         // Watershed of each cell, numbered by outlet
+  num_basins:   uint
+}
+
+// Each cell's region in grid order, see basin_regions and voronoi_regions
+struct Regions {
+  labels: ~[uint], // From 0 to count - 1
+  count:  uint
+}
+
+// Line of sight from an observer, see compute_viewshed
+struct Viewshed {
+  cells:   ~[Option<bool>], // Whether each cell can be seen, None beyond the range
+  visible: uint
+}
+
+// A stream from its source or a confluence to the next confluence or outlet
+struct River {
+  points:       ~[Vec2<f32>], // Grid x, y
+  accumulation: f32,          // At the last point
+  basin:        uint
+}
+
+struct TextVertex {
+  position: Vec2<GLfloat>, // Pixels from the top left of the window
+  texcoord: Vec2<GLfloat>
+}
+
+impl VertexFormat for TextVertex {
+  fn attributes(_: Option<TextVertex>) -> ~[VertexAttribute] {
+    ~[vertex_attribute!(TextVertex, position, "position"),
+      vertex_attribute!(TextVertex, texcoord, "texcoord")]
+  }
+}
+
+struct TextRenderer {
+  program:          GLuint,
+  vertex_array_id:  GLuint,
+  vertex_buffer_id: GLuint,
+  font_texture_id:  GLuint,
+
+  screen_size: Uniform<Vec2<f32>>,
+  color:       Uniform<Vec4<f32>>,
+  font:        Uniform<i32>
+}
+
+// A camera pose at a given time (in seconds) along a flythrough path
+struct Keyframe {
+  time:        f32,
+  translation: Vec3<f32>,
+  rotation:    Quat<f32>,
+  scale:       f32
+}
+
+// What to do with the terrain once loaded. Commands other than View write
+// their results and exit without opening a window.
+enum Command {
+  View,
+  ExportRasters(~str),  // Directory to write the rasters to
+  PrintStats,
+  ExportDrainage(~str), // Directory to write the streams and flow accumulation to
+  ExportRegions(~str)   // File to write the region labels to
+}
+
+// Command line options
+struct Options {
+  command:    Command,
+  heightmap:  Option<~str>, // Instead of PNG_SRC
+  raster_depth: uint,       // Bits per sample of exported rasters, 8 or 16
+  stats_json: Option<~str>, // File to also write the statistics to
+  sea_level:  Option<f32>,
+  flythrough: Option<~str>, // Keyframe file to play back
+  headless:   Option<~str>, // Directory to write frames to instead of opening a window
+  bindings:   Option<~str>, // Key binding overrides
+  record:     Option<~str>, // File to record input events to
+  replay:     Option<~str>, // Recorded input events to play back
+  time_of_day: Option<f32>, // Start the day/night cycle at this hour
+  land_use:   Option<~str>, // Control map assigning land use classes to cells
+  fog_density: Option<f32>,
+  fog_color:  Option<Vec3<f32>>, // Instead of the horizon color
+  wireframe_color: Option<Vec3<f32>>,
+  wireframe_width: Option<f32>,
+  contour_interval: Option<f32>,
+  contours_geojson: Option<~str>, // Files to write the contours to
+  contours_svg:     Option<~str>,
+  flow_method:      FlowMethod,
+  stream_threshold: f32,
+  region_seeds:     Option<~str>, // Points to grow Voronoi regions from instead of using basins
+  observer:         Option<(u32, u32)>, // Cell to compute a viewshed from at startup
+  observer_height:  Option<f32>,
+  view_range:       Option<f32>,
+  viewshed_png:     Option<~str> // Written again whenever the observer is moved
+}
+
+// An input event as it was handed to handle_window_event, tagged with the
+// frame it was processed in so replays are independent of the frame rate
+struct RecordedEvent {
+  frame: u64,
+  time:  f64,
+  event: glfw::WindowEvent
+}
+
+// Everything a key can be bound to
+#[deriving(Eq)]
+enum KeyAction {
+  Quit,
+  MoveNorth,
+  MoveSouth,
+  MoveWest,
+  MoveEast,
+  RotateXCw,
+  RotateXCcw,
+  RotateYCw,
+  RotateYCcw,
+  RotateZCw,
+  RotateZCcw,
+  ZoomIn,
+  ZoomOut,
+  DimSunlight,
+  BrightenSunlight,
+  ToggleWireframe,
+  PrintKeyframe,
+  ToggleHud,
+  ToggleHelp,
+  RefreshWindow,
+  ToggleDayCycle,
+  AdvanceTimeOfDay,
+  RewindTimeOfDay,
+  ToggleShadows,
+  ToggleLightmap,
+  RaiseSeaLevel,
+  LowerSeaLevel,
+  ToggleReflections,
+  ToggleTriplanar,
+  ToggleLandUse,
+  ToggleFog,
+  DenserFog,
+  ThinnerFog,
+  ToggleScattering,
+  ToggleChunkLines,
+  CycleDebugView,
+  ToggleNormalLines,
+  ToggleFlatShading,
+  ToggleContours,
+  FinerContours,
+  CoarserContours,
+  ToggleRivers,
+  ToggleRegions,
+  PlaceObserver,
+  ToggleViewshed
+}
+
+struct KeyBinding {
+  key:    glfw::Key,
+  mods:   glfw::Modifiers,
+  action: KeyAction
+}
+
 enum Compass {
   North,
   South,
@@ -197,6 +1103,120 @@ fn start(argc: int, argv: **u8) -> int {
   native::start(argc, argv, main)
 }
 
+fn parse_options(args: &[~str]) -> Options {
+  let mut options = Options { command: View, heightmap: None, raster_depth: 8, stats_json: None, sea_level: None,
+                             flythrough: None, headless: None, bindings: None, record: None, replay: None,
+                             time_of_day: None, land_use: None, fog_density: None, fog_color: None,
+                             wireframe_color: None, wireframe_width: None, contour_interval: None,
+                             contours_geojson: None, contours_svg: None, flow_method: D8,
+                             stream_threshold: STREAM_THRESHOLD,
+                             region_seeds: None, observer: None, observer_height: None,
+                             view_range: None, viewshed_png: None };
+  let mut i = 1;
+
+  // A leading word names a command, e.g. "rasters out" for export_rasters
+  if args.len() > 1 && !args[1].starts_with("--") {
+    options.command = match args[1].as_slice() {
+      "rasters" if args.len() > 2 => { i = 3; ExportRasters(args[2].clone()) }
+      "rasters" => fail!("Missing output directory for rasters"),
+      "stats"   => { i = 2; PrintStats }
+      "rivers" if args.len() > 2 => { i = 3; ExportDrainage(args[2].clone()) }
+      "rivers"  => fail!("Missing output directory for rivers"),
+      "regions" if args.len() > 2 => { i = 3; ExportRegions(args[2].clone()) }
+      "regions" => fail!("Missing output file for regions"),
+      command   => fail!("Unknown command: {}", command)
+    };
+  }
+
+  while i < args.len() {
+    if i + 1 >= args.len() {
+      fail!("Missing value for {}", args[i]);
+    }
+    let value = Some(args[i + 1].clone());
+
+    match args[i].as_slice() {
+      "--flythrough" => options.flythrough = value,
+      "--headless"   => options.headless = value,
+      "--bindings"   => options.bindings = value,
+      "--record"     => options.record = value,
+      "--replay"     => options.replay = value,
+      "--land-use"   => options.land_use = value,
+      "--heightmap"  => options.heightmap = value,
+      "--stats-json" => options.stats_json = value,
+      "--sea-level"  => options.sea_level = match from_str::<f32>(args[i + 1].as_slice()) {
+        Some(level) => Some(level),
+        None => fail!("Invalid sea level: {}", args[i + 1])
+      },
+      "--raster-depth" => options.raster_depth = match from_str::<uint>(args[i + 1].as_slice()) {
+        Some(8) => 8,
+        Some(16) => 16,
+        _ => fail!("Invalid raster depth, expected 8 or 16: {}", args[i + 1])
+      },
+      "--time-of-day" => options.time_of_day = match from_str::<f32>(args[i + 1].as_slice()) {
+        Some(hours) => Some(hours),
+        None => fail!("Invalid time of day: {}", args[i + 1])
+      },
+      "--fog-density" => options.fog_density = match from_str::<f32>(args[i + 1].as_slice()) {
+        Some(density) => Some(density),
+        None => fail!("Invalid fog density: {}", args[i + 1])
+      },
+      "--fog-color"  => options.fog_color = Some(parse_color(args[i + 1].as_slice())),
+      "--wireframe-color" => options.wireframe_color = Some(parse_color(args[i + 1].as_slice())),
+      "--wireframe-width" => options.wireframe_width = match from_str::<f32>(args[i + 1].as_slice()) {
+        Some(width) => Some(width),
+        None => fail!("Invalid wireframe width: {}", args[i + 1])
+      },
+      "--contour-interval" => options.contour_interval = match from_str::<f32>(args[i + 1].as_slice()) {
+        Some(interval) if interval > 0.0 => Some(interval),
+        _ => fail!("Invalid contour interval: {}", args[i + 1])
+      },
+      "--contours-geojson" => options.contours_geojson = value,
+      "--contours-svg" => options.contours_svg = value,
+      "--flow-method" => options.flow_method = match args[i + 1].as_slice() {
+        "d8"   => D8,
+        "dinf" => DInfinity,
+        method => fail!("Invalid flow method, expected d8 or dinf: {}", method)
+      },
+      "--stream-threshold" => options.stream_threshold = match from_str::<f32>(args[i + 1].as_slice()) {
+        Some(cells) if cells >= 1.0 => cells,
+        _ => fail!("Invalid stream threshold: {}", args[i + 1])
+      },
+      "--region-seeds" => options.region_seeds = value,
+      "--observer" => options.observer = Some(parse_cell(args[i + 1].as_slice())),
+      "--observer-height" => options.observer_height = match from_str::<f32>(args[i + 1].as_slice()) {
+        Some(height) if height >= 0.0 => Some(height),
+        _ => fail!("Invalid observer height: {}", args[i + 1])
+      },
+      "--view-range" => options.view_range = match from_str::<f32>(args[i + 1].as_slice()) {
+        Some(range) if range > 0.0 => Some(range),
+        _ => fail!("Invalid view range: {}", args[i + 1])
+      },
+      "--viewshed-png" => options.viewshed_png = value,
+      arg            => fail!("Unknown argument: {}", arg)
+    }
+    i += 2;
+  }
+  options
+}
+
+// Parses a color given as "r,g,b" with each component from 0 to 1
+fn parse_color(text: &str) -> Vec3<f32> {
+  let components: ~[Option<f32>] = text.split(',').map(|c| from_str::<f32>(c.trim())).collect();
+  match components.as_slice() {
+    [Some(r), Some(g), Some(b)] => Vec3::new(r, g, b),
+    _ => fail!("Invalid color: {}", text)
+  }
+}
+
+// Parses a grid cell given as "x,y", like the HUD shows under the cursor
+fn parse_cell(text: &str) -> (u32, u32) {
+  let components: ~[Option<u32>] = text.split(',').map(|c| from_str::<u32>(c.trim())).collect();
+  match components.as_slice() {
+    [Some(x), Some(y)] => (x, y),
+    _ => fail!("Invalid cell: {}", text)
+  }
+}
+
 // Terrain initialization  -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- --
 
 fn load_png_image(file_path: &str) -> png::Image {
@@ -445,21 +1465,214 @@ fn box_filter_heightmap(heightmap: ~[u8], width: u32, height: u32, smoothen_edge
   filtered_map
 }
 
+// Relief lightmap -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- --
+
+// Unit step across the grid for an azimuth in degrees clockwise from north.
+// Grid x follows the heightmap rows (north to south), grid y its columns (west
+// to east).
+fn azimuth_step(azimuth: f32) -> (f32, f32) {
+  let a = azimuth.to_radians();
+  (-a.cos(), a.sin())
+}
+
+// Elevation angle (radians) of the highest terrain seen from a grid point when
+// looking along (dx, dy), sampling at increasing distances up to the last step
+fn horizon_angle(grid: &HeightGrid, x: f32, y: f32, dx: f32, dy: f32) -> f32 {
+  let origin = grid.sample(x, y);
+  let mut max_slope = 0.0f32;
+
+  for &distance in LIGHTMAP_STEPS.iter() {
+    let sx = x + dx * distance;
+    let sy = y + dy * distance;
+    if !grid.contains(sx, sy) { break }
+
+    max_slope = max_slope.max((grid.sample(sx, sy) - origin) / distance);
+  }
+  max_slope.atan()
+}
+
+// Computes per-vertex ambient occlusion and sun visibility from horizon angles
+// in LIGHTMAP_DIRECTIONS directions around each grid point. Returns RGB bytes
+// in grid order with occlusion-free sky fraction in red, sun visibility in
+// green and blue unused.
+fn compute_lightmap(grid: &HeightGrid) -> ~[u8] {
+  let mut lightmap: ~[u8] = ~[];
+
+  let directions: ~[(f32, f32)] = range(0, LIGHTMAP_DIRECTIONS)
+    .map(|i| azimuth_step(i as f32 * 360.0 / LIGHTMAP_DIRECTIONS as f32))
+    .collect();
+
+  let (sun_dx, sun_dy) = azimuth_step(LIGHTMAP_SUN_AZIMUTH);
+  let penumbra = LIGHTMAP_PENUMBRA.to_radians();
+
+  for x in range(0, grid.width) {
+    for y in range(0, grid.height) {
+      let (fx, fy) = (x as f32, y as f32);
+
+      let mut occlusion = 0.0f32;
+      for &(dx, dy) in directions.iter() {
+        occlusion += horizon_angle(grid, fx, fy, dx, dy).sin();
+      }
+      let sky = 1.0 - occlusion / LIGHTMAP_DIRECTIONS as f32;
+
+      let sun_horizon = horizon_angle(grid, fx, fy, sun_dx, sun_dy);
+      let sun = smoothstep(-penumbra, penumbra, LIGHTMAP_SUN_ALTITUDE.to_radians() - sun_horizon);
+
+      lightmap.push_all(&[(sky * 255.0) as u8, (sun * 255.0) as u8, 0]);
+    }
+  }
+  lightmap
+}
+
+// Lightmaps are cached next to the heightmaps as PNGs, keyed by a hash of the
+// raw heightmap and the lightmap parameters, since computing them takes a while
+fn load_or_compute_lightmap(grid: &HeightGrid, heightmap_hash: u64) -> ~[u8] {
+  let parameters = format!("{}:{}:{:?}:{}:{}:{}", heightmap_hash, LIGHTMAP_DIRECTIONS, LIGHTMAP_STEPS,
+                           LIGHTMAP_SUN_AZIMUTH, LIGHTMAP_SUN_ALTITUDE, LIGHTMAP_PENUMBRA);
+  let cache_path = std::os::getcwd().join(format!("lightmap_{:016x}.png", std::hash::hash(&parameters)));
+
+  if cache_path.exists() {
+    match png::load_png(&cache_path) {
+      Ok(image) => {
+        if image.width == grid.height && image.height == grid.width {
+          return image.pixels
+        }
+      }
+      Err(_) => {}
+    }
+  }
+
+  let lightmap = compute_lightmap(grid);
+  let image = png::Image { width: grid.height, height: grid.width, color_type: png::RGB8, pixels: lightmap.clone() };
+
+  match png::store_png(&image, &cache_path) {
+    Ok(()) => {},
+    Err(s) => println!("Could not cache lightmap: {}", s)
+  }
+  lightmap
+}
+
 // Shader compilation and initialization  -- -- -- -- -- -- -- -- -- -- -- -- --
 
-fn load_shader_file(file_name: &str) -> ~str {
+// A shader with its includes expanded. The #line directives left in the text
+// number every file as a GLSL source string, files[n] being source string n.
+struct ShaderSource {
+  text:    ~str,
+  files:   ~[~str],
+  version: uint
+}
+
+impl ShaderSource {
+  // Before GLSL 3.30, #line gave the number of the directive's own line
+  fn line_directive(&self, line: uint, file: uint) -> ~str {
+    let line = if self.version < 330 { line - 1 } else { line };
+    "#line ".to_owned() + line.to_str() + " " + file.to_str() + "\n"
+  }
+
+  // Replaces the source string numbers in a compile log with the file names.
+  // Drivers differ, giving e.g. "0(12) : error", "0:12(5): error" or "ERROR: 0:12:".
+  fn map_log(&self, log: &str) -> ~str {
+    let lines: ~[~str] = log.lines().map(|line| {
+      let start = ["ERROR: ", "WARNING: "].iter().find(|prefix| line.starts_with(**prefix)).map_or(0, |prefix| prefix.len());
+      let rest = line.slice_from(start);
+      let digits = rest.find(|c: char| !c.is_digit()).unwrap_or(rest.len());
+
+      match from_str::<uint>(rest.slice_to(digits)) {
+        Some(index) if index < self.files.len() && (rest.slice_from(digits).starts_with(":") ||
+                                                    rest.slice_from(digits).starts_with("(")) =>
+          format!("{}{}{}", line.slice_to(start), self.files[index], rest.slice_from(digits)),
+        _ => line.to_owned()
+      }
+    }).collect();
+    lines.connect("\n")
+  }
+}
+
+// Defines given to every shader, after its #version
+fn shader_defines() -> ~[(&'static str, ~str)] {
+  let mut defines = ~[("NUM_WAVES", WAVES.len().to_str()),
+                      ("NUM_CASCADES", NUM_CASCADES.to_str()),
+                      ("MAX_MATERIALS", MAX_MATERIALS.to_str())];
+  if SHADOW_MAPS {
+    defines.push(("ENABLE_SHADOWS", ~""));
+  }
+  defines
+}
+
+fn load_text_file(file_name: &str) -> ~str {
   let p = std::os::getcwd().join(Path::new(file_name));
   match File::open(&p).read_to_end() {
     Ok(s) => str::from_utf8_owned(s).unwrap(),
-    Err(s) => fail!(s)
+    Err(s) => fail!("{}: {}", file_name, s)
+  }
+}
+
+// Loads a shader, expanding #include "file" relative to the including file.
+// Each file is included at most once, so shared files need no include guards.
+fn load_shader_file(file_name: &str) -> ShaderSource {
+  let mut source = ShaderSource { text: ~"", files: ~[], version: 110 };
+  include_shader_file(&mut source, &Path::new(file_name), 0);
+  source
+}
+
+fn include_shader_file(source: &mut ShaderSource, path: &Path, depth: uint) {
+  let index = source.files.len();
+  let name = format!("{}", path.display());
+  source.files.push(name.clone());
+
+  let text = load_text_file(name);
+
+  for (i, line) in text.lines().enumerate() {
+    let directive = line.trim_left();
+
+    if directive.starts_with("#include") {
+      let file = directive.slice_from("#include".len()).trim();
+      if file.len() < 2 || !file.starts_with("\"") || !file.ends_with("\"") {
+        fail!("{}:{}: Expected \\#include \"file\"", name, i + 1);
+      }
+      if depth == MAX_INCLUDE_DEPTH {
+        fail!("{}:{}: Includes nested more than {} deep", name, i + 1, MAX_INCLUDE_DEPTH);
+      }
+
+      let included = path.dir_path().join(file.slice(1, file.len() - 1));
+      if !source.files.contains(&format!("{}", included.display())) {
+        let marker = source.line_directive(1, source.files.len());
+        source.text.push_str(marker);
+        include_shader_file(source, &included, depth + 1);
+        let marker = source.line_directive(i + 2, index);
+        source.text.push_str(marker);
+      }
+    } else {
+      source.text.push_str(line);
+      source.text.push_char('\n');
+
+      // #version must come first, so the defines follow it
+      if depth == 0 && directive.starts_with("#version") {
+        source.version = directive.words().nth(1).and_then(|v| from_str(v)).unwrap_or(110);
+        for &(define, ref value) in shader_defines().iter() {
+          source.text.push_str(format!("\\#define {} {}\n", define, *value));
+        }
+        let marker = source.line_directive(i + 2, index);
+        source.text.push_str(marker);
+      }
+    }
+  }
+}
+
+// Loads a shader for an optional stage, or None if its file doesn't exist
+fn load_optional_shader_file(file_name: &str) -> Option<ShaderSource> {
+  if std::os::getcwd().join(Path::new(file_name)).exists() {
+    Some(load_shader_file(file_name))
+  } else {
+    None
   }
 }
 
-fn compile_shader(src: &str, ty: GLenum) -> GLuint {
+fn compile_shader(source: &ShaderSource, ty: GLenum) -> GLuint {
   let shader = gl::CreateShader(ty);
   unsafe {
     // Attempt to compile the shader
-    src.with_c_str(|ptr| gl::ShaderSource(shader, 1, &ptr, ptr::null()));
+    source.text.with_c_str(|ptr| gl::ShaderSource(shader, 1, &ptr, ptr::null()));
     gl::CompileShader(shader);
 
     // Get the compile status
@@ -472,16 +1685,17 @@ fn compile_shader(src: &str, ty: GLenum) -> GLuint {
       gl::GetShaderiv(shader, gl::INFO_LOG_LENGTH, &mut len);
       let mut buf = vec::from_elem(len as uint - 1, 0u8);     // subtract 1 to skip the trailing null character
       gl::GetShaderInfoLog(shader, len, ptr::mut_null(), buf.as_mut_ptr() as *mut GLchar);
-      fail!(str::raw::from_utf8(buf).to_owned());
+      fail!(source.map_log(str::raw::from_utf8(buf)));
     }
   }
   shader
 }
 
-fn link_program(vs: GLuint, fs: GLuint) -> GLuint {
+fn link_program(shaders: &[GLuint]) -> GLuint {
   let program = gl::CreateProgram();
-  gl::AttachShader(program, vs);
-  gl::AttachShader(program, fs);
+  for &shader in shaders.iter() {
+    gl::AttachShader(program, shader);
+  }
   gl::LinkProgram(program);
   unsafe {
     // Get the link status
@@ -500,14 +1714,74 @@ fn link_program(vs: GLuint, fs: GLuint) -> GLuint {
   program
 }
 
-////////////////////////////////////////////////////////////////////////////////
-
-fn main() {
+// Compiles and links the given stages, e.g. (gl::GEOMETRY_SHADER, source).
+// Stages may be any of vertex, tessellation control and evaluation, geometry
+// and fragment, in any order; optional ones are simply left out of the list.
+fn build_program(stages: &[(GLenum, &ShaderSource)]) -> GLuint {
+  let shaders: ~[GLuint] = stages.iter().map(|&(kind, src)| compile_shader(src, kind)).collect();
+  let program = link_program(shaders);
+  bind_uniform_blocks(program);
+
+  // The program keeps what it needs once linked
+  for &shader in shaders.iter() {
+    gl::DeleteShader(shader);
+  }
+  program
+}
 
-  if DEBUG { print!("Loading heightmap from png: {}... ", PNG_SRC); flush(); }
+// Uniform blocks of common.glsl with their binding points and the size of the
+// Rust struct filling them
+fn uniform_blocks() -> ~[(&'static str, GLuint, uint)] {
+  ~[("Camera", CAMERA_BLOCK_BINDING, mem::size_of::<CameraBlock>()),
+    ("Light",  LIGHT_BLOCK_BINDING,  mem::size_of::<LightBlock>())]
+}
 
-  let image = load_png_image(PNG_SRC.to_owned());
-  let heightmap = image.pixels.clone();
+// Points the blocks a program uses at their uniform buffers. A size differing
+// from the Rust struct means the std140 layouts have drifted apart.
+fn bind_uniform_blocks(program: GLuint) {
+  for &(name, binding, size) in uniform_blocks().iter() {
+    let index = name.with_c_str(|ptr| gl::GetUniformBlockIndex(program, ptr));
+    if index == gl::INVALID_INDEX { continue }
+
+    let mut data_size: GLint = 0;
+    unsafe { gl::GetActiveUniformBlockiv(program, index, gl::UNIFORM_BLOCK_DATA_SIZE, &mut data_size) };
+    if data_size as uint != size {
+      println!("Warning: uniform block {} is {} bytes in the shader but {} bytes in Rust", name, data_size, size);
+    }
+    gl::UniformBlockBinding(program, index, binding);
+  }
+}
+
+unsafe fn initialize_uniform_buffer<T>(binding: GLuint) -> GLuint {
+  let mut buffer_id = 0;
+  gl::GenBuffers(1, &mut buffer_id);
+  gl::BindBuffer(gl::UNIFORM_BUFFER, buffer_id);
+  gl::BufferData(gl::UNIFORM_BUFFER, mem::size_of::<T>() as GLsizeiptr, ptr::null(), gl::DYNAMIC_DRAW);
+  gl::BindBufferBase(gl::UNIFORM_BUFFER, binding, buffer_id);
+  buffer_id
+}
+
+unsafe fn update_uniform_buffer<T>(buffer_id: GLuint, block: &T) {
+  gl::BindBuffer(gl::UNIFORM_BUFFER, buffer_id);
+  gl::BufferSubData(gl::UNIFORM_BUFFER, 0, mem::size_of::<T>() as GLsizeiptr, cast::transmute(block));
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+fn main() {
+
+  let options = parse_options(std::os::args());
+
+  let heightmap_file = match options.heightmap {
+    Some(ref file) => file.clone(),
+    None => PNG_SRC.to_owned()
+  };
+
+  if DEBUG { print!("Loading heightmap from png: {}... ", heightmap_file); flush(); }
+
+  let image = load_png_image(heightmap_file);
+  let heightmap = image.pixels.clone();
+  let heightmap_hash = std::hash::hash(&heightmap);
   let width = image.width.clone();
   let height = image.height.clone();
 
@@ -519,9 +1793,45 @@ fn main() {
 
   if DEBUG { print!("Computing vertices... "); flush(); }
   let filtered = box_filter_heightmap(heightmap, width, height, true);
+  let grid = HeightGrid { width: width, height: height, heights: filtered.clone() };
   let vertices = initialize_vertices(filtered, width, height);
   if DEBUG { println!("done. ({} vertices)", vertices.len()) }
 
+  match options.sea_level {
+    Some(level) => unsafe { sea_level = level },
+    None => {}
+  }
+
+  match options.command {
+    ExportRasters(ref output_dir) => {
+      export_rasters(&grid, output_dir.as_slice(), options.raster_depth);
+      return
+    }
+    PrintStats => {
+      let stats = terrain_stats(&grid, unsafe { sea_level });
+      print_stats(&stats);
+      match options.stats_json {
+        Some(ref file) => write_text_file(file.as_slice(), stats_json(&stats)),
+        None => {}
+      }
+      return
+    }
+    ExportDrainage(ref output_dir) => {
+      export_drainage(&grid, output_dir.as_slice(), options.flow_method, options.stream_threshold, options.raster_depth);
+      return
+    }
+    ExportRegions(ref file) => {
+      let regions = match options.region_seeds {
+        Some(ref seeds) => voronoi_regions(&grid, load_region_seeds(seeds.as_slice(), &grid)),
+        None => basin_regions(&analyze_drainage(&grid, options.flow_method))
+      };
+      if DEBUG { println!("Segmented {} regions", regions.count) }
+      write_region_labels(&grid, &regions, file.as_slice());
+      return
+    }
+    View => {}
+  }
+
   if DEBUG { print!("Computing texcoords... "); flush(); }
   let texcoords = initialize_texcoords(width, height);
   if DEBUG { println!("done. ({} texcoords)", texcoords.len()) }
@@ -534,6 +1844,20 @@ fn main() {
   let indices = initialize_indices(width, height);
   if DEBUG { println!("done. ({} indices)", indices.len()) }
 
+  if DEBUG { print!("Computing lightmap... "); flush(); }
+  let lightmap = load_or_compute_lightmap(&grid, heightmap_hash);
+  if DEBUG { println!("done. ({} bytes)", lightmap.len()) }
+
+  let land_use = match options.land_use {
+    Some(ref file) => {
+      if DEBUG { print!("Loading land use map... "); flush(); }
+      let classes = load_land_use(file.as_slice(), &grid);
+      if DEBUG { println!("done. ({} cells)", classes.len()) }
+      classes
+    }
+    None => ~[]
+  };
+
   if DEBUG { print!("Creating VNTs... "); flush(); }
   let vnts = initialize_vnts(vertices.clone(), normals.clone(), texcoords.clone());
   if DEBUG { println!("done. ({} VNTs, {} bytes)", vnts.len(), mem::size_of::<Vertex>() * vnts.len()) }
@@ -542,13 +1866,116 @@ fn main() {
     initialize_world();
     initialize_camera();
     initialize_screen();
+
+    match options.time_of_day {
+      Some(hours) => { day_cycle = true; update_time_of_day(hours) }
+      None => {}
+    }
+
+    match options.fog_density {
+      Some(density) => fog_density = density,
+      None => {}
+    }
+    fog_color = options.fog_color;
+
+    match options.wireframe_color {
+      Some(color) => wireframe_color = Vec4::new(color.x, color.y, color.z, 1.0),
+      None => {}
+    }
+    match options.wireframe_width {
+      Some(width) => wireframe_width = width,
+      None => {}
+    }
+    match options.contour_interval {
+      Some(interval) => contour_interval = interval,
+      None => {}
+    }
+    match options.observer_height {
+      Some(height) => observer_height = height,
+      None => {}
+    }
+    match options.view_range {
+      Some(range) => view_range = range,
+      None => {}
+    }
+  }
+
+  // The viewer traces contours for labels when they are first shown, so this is
+  // only done up front for export
+  if options.contours_geojson.is_some() || options.contours_svg.is_some() {
+    if DEBUG { print!("Tracing contours... "); flush(); }
+    let contours = extract_contours(&grid, unsafe { contour_interval });
+    if DEBUG { println!("done. ({} lines)", contours.len()) }
+
+    match options.contours_geojson {
+      Some(ref file) => write_contours_geojson(contours, file.as_slice()),
+      None => {}
+    }
+    match options.contours_svg {
+      Some(ref file) => write_contours_svg(contours, width, height, file.as_slice()),
+      None => {}
+    }
   }
 
+  let seeds = match options.region_seeds {
+    Some(ref file) => load_region_seeds(file.as_slice(), &grid),
+    None => ~[]
+  };
+
+  let viewshed = match options.observer {
+    Some((x, y)) if !grid.contains(x as f32, y as f32) => fail!("Observer outside the terrain: {},{}", x, y),
+    Some(cell) => {
+      if DEBUG { print!("Computing viewshed... "); flush(); }
+      let viewshed = unsafe { compute_viewshed(&grid, cell, observer_height, view_range) };
+      if DEBUG { println!("done. ({} cells visible)", viewshed.visible) }
+
+      match options.viewshed_png {
+        Some(ref file) => write_viewshed_mask(&grid, &viewshed, file.as_slice()),
+        None => {}
+      }
+      Some(viewshed)
+    }
+    None => None
+  };
+
   // Start OpenGL -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- --
 
   let vs_src = load_shader_file(VS_SRC);
   let fs_src = load_shader_file(FS_SRC);
-  let gs_src = load_shader_file(GS_SRC);
+  let gs_src = load_optional_shader_file(GS_SRC);
+  let hud_vs_src = load_shader_file(HUD_VS_SRC);
+  let hud_fs_src = load_shader_file(HUD_FS_SRC);
+  let shadow_vs_src = load_shader_file(SHADOW_VS_SRC);
+  let shadow_fs_src = load_shader_file(SHADOW_FS_SRC);
+  let water_vs_src = load_shader_file(WATER_VS_SRC);
+  let water_fs_src = load_shader_file(WATER_FS_SRC);
+  let sky_vs_src = load_shader_file(SKY_VS_SRC);
+  let sky_fs_src = load_shader_file(SKY_FS_SRC);
+  let wireframe_vs_src = load_shader_file(WIREFRAME_VS_SRC);
+  let wireframe_fs_src = load_shader_file(WIREFRAME_FS_SRC);
+
+  let keyframes = match options.flythrough {
+    Some(ref file) => load_keyframes(file.as_slice()),
+    None => ~[]
+  };
+
+  let bindings = match options.bindings {
+    Some(ref file) => load_key_bindings(file.as_slice()),
+    None => default_key_bindings()
+  };
+
+  let replay = match options.replay {
+    Some(ref file) => load_recording(file.as_slice()),
+    None => ~[]
+  };
+
+  let mut recording = match options.record {
+    Some(ref file) => match File::create(&Path::new(file.as_slice())) {
+      Ok(f) => Some(f),
+      Err(s) => fail!(s)
+    },
+    None => None
+  };
 
   glfw::set_error_callback(~ErrorContext);
 
@@ -559,25 +1986,36 @@ fn main() {
     glfw::window_hint::opengl_profile(glfw::OpenGlCoreProfile);
     glfw::window_hint::opengl_forward_compat(true);
 
-    let window = glfw::Window::create(1920, 1280, "OpenGL", glfw::Windowed).unwrap();
+    // Headless rendering still needs a context, so create the window but keep it hidden
+    glfw::window_hint::visible(options.headless.is_none());
+
+    let window = glfw::Window::create(WINDOW_WIDTH, WINDOW_HEIGHT, "OpenGL", glfw::Windowed).unwrap();
     window.set_key_polling(true);
+    window.set_cursor_pos_polling(true);
+    window.set_size_polling(true);
+    window.set_framebuffer_size_polling(true);
     window.make_context_current();
 
     // Load the OpenGL function pointers
     gl::load_with(glfw::get_proc_address);
 
-    // Create GLSL shaders
-    let vertex_shader   = compile_shader(vs_src, gl::VERTEX_SHADER);
-    let fragment_shader = compile_shader(fs_src, gl::FRAGMENT_SHADER);
-    // let geometry_shader = compile_shader(gs_src, gl::GEOMETRY_SHADER);
-    let shader_program = link_program(vertex_shader, fragment_shader); // , geometry_shader);
+    // Create GLSL shaders, with the geometry stage only if test.geom exists
+    let mut stages = ~[(gl::VERTEX_SHADER, &vs_src)];
+    match gs_src {
+      Some(ref src) => {
+        stages.push((gl::GEOMETRY_SHADER, src));
+        unsafe { flat_shading_available = true }
+      }
+      None => if DEBUG { println!("No geometry shader ({}), flat shading is unavailable", GS_SRC) }
+    }
+    stages.push((gl::FRAGMENT_SHADER, &fs_src));
+
+    let shader_program = build_program(stages);
 
     let mut vertex_array_id = 0;
     let mut vnt_buffer_id = 1;
     let mut index_buffer_id = 2;
 
-    let mut grass_texture_id = 1;
-
     unsafe {
 
       // Create Vertex Array Object and Vertex Buffer Objects
@@ -594,36 +2032,13 @@ fn main() {
       gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, index_buffer_id);
       gl::BufferData(gl::ELEMENT_ARRAY_BUFFER, indices_bytes, indices_ptr, gl::STATIC_DRAW);
 
-      gl::GenTextures(1, &mut grass_texture_id);
-      gl::BindTexture(gl::TEXTURE_2D, grass_texture_id);
-
-      let tex = load_png_image(TEX_SRC);
-      let tex_height = tex.height.clone();
-      let tex_width = tex.width.clone();
-      let data = tex.pixels;
-
-      gl::TexImage2D(gl::TEXTURE_2D, 0, gl::RGBA as GLint, tex_width as GLint, tex_height as GLint, 0, gl::RGBA, gl::UNSIGNED_BYTE, data.as_ptr() as GLeglImageOES);
-
-      gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
-      gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
-      gl::GenerateMipmap(gl::TEXTURE_2D);
-
       // Use shader program
       gl::UseProgram(shader_program);
 
       initialize_shader_data(shader_program);
+      initialize_materials(shader_program);
 
-      gl::EnableVertexAttribArray(0);
-
-      let stride = mem::size_of::<Vec2<GLfloat>>() + mem::size_of::<Vec3<GLfloat>>()+ mem::size_of::<Vec3<GLfloat>>();
-
-      gl::VertexAttribPointer(0, 3, gl::FLOAT, gl::FALSE, stride as GLint, ptr::null());
-
-      let normals_offset  = cast::transmute(mem::size_of::<Vec3<GLfloat>>());
-      gl::VertexAttribPointer(1, 2, gl::FLOAT, gl::FALSE, stride as GLint, normals_offset);
-
-      let texcoords_offset  = cast::transmute(mem::size_of::<Vec3<GLfloat>>() + mem::size_of::<Vec2<GLfloat>>());
-      gl::VertexAttribPointer(2, 3, gl::FLOAT, gl::FALSE, stride as GLint, texcoords_offset);
+      initialize_vertex_layout::<Vertex>(shader_program);
 
       gl::Enable(gl::DEPTH_TEST);
       gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
@@ -632,38 +2047,181 @@ fn main() {
       gl::FrontFace(gl::CW);
     }
 
+    let (min_height, max_height) = grid.heights.iter().fold((std::f32::INFINITY, -std::f32::INFINITY),
+      |(lo, hi), &h| (lo.min(h), hi.max(h)));
+    unsafe { sea_level_range = (min_height, max_height) }
+
+    // The normal lines are drawn with the wireframe program
+    let wireframe = unsafe { initialize_wireframe(&wireframe_vs_src, &wireframe_fs_src) };
+    let normal_lines = unsafe { initialize_normal_lines(wireframe.program, vertices, normals, width, height) };
+
+    let mut scene = Scene {
+      program:         shader_program,
+      vertex_array_id: vertex_array_id,
+      num_indices:     indices.len(),
+
+      terrain_min: Vec3::new(0.0, 0.0, -max_height),
+      terrain_max: Vec3::new((grid.width - 1) as f32, (grid.height - 1) as f32, -min_height),
+
+      shadow_map: unsafe { initialize_shadow_map(&shadow_vs_src, &shadow_fs_src) },
+
+      camera_buffer_id: unsafe { initialize_uniform_buffer::<CameraBlock>(CAMERA_BLOCK_BINDING) },
+      light_buffer_id:  unsafe { initialize_uniform_buffer::<LightBlock>(LIGHT_BLOCK_BINDING) },
+
+      lightmap_texture_id: unsafe { initialize_lightmap_texture(lightmap, &grid) },
+      height_texture_id:   unsafe { initialize_height_texture(&grid) },
+      material_texture_id: unsafe { initialize_material_textures() },
+      land_use_texture_id: if land_use.len() > 0 { unsafe { initialize_land_use_texture(land_use, &grid) } } else { 0 },
+      rivers_texture_id:   unsafe { initialize_overlay_texture(&grid, gl::LINEAR) },
+      regions_texture_id:  unsafe { initialize_overlay_texture(&grid, gl::NEAREST) },
+      viewshed_texture_id: unsafe { initialize_overlay_texture(&grid, gl::NEAREST) },
+
+      sky:   unsafe { initialize_sky(&sky_vs_src, &sky_fs_src) },
+      water: unsafe { initialize_water(&water_vs_src, &water_fs_src, &grid) },
+      wireframe: wireframe,
+      normal_lines: normal_lines
+    };
+
+    match viewshed {
+      Some(ref viewshed) => unsafe {
+        update_overlay_texture(scene.viewshed_texture_id, viewshed_pixels(viewshed), &grid);
+        viewshed_enabled = true;
+      },
+      None => {}
+    }
+
+    let text_renderer = unsafe { initialize_text_renderer(&hud_vs_src, &hud_fs_src) };
+    let mut hud = Hud {
+      visible:   true,
+      show_help: false,
+      fps:       0,
+      frame_ms:  0.0,
+      status:    ~"",
+      help:      bindings_help(bindings),
+      land_use:  land_use_counts(land_use),
+      contour_labels: ~[],
+      labelled_interval: None
+    };
+
+    let mut analysis = Analysis {
+      flow_method:      options.flow_method,
+      stream_threshold: options.stream_threshold,
+      drainage:         None,
+      rivers_drawn:     false,
+      region_seeds:     seeds,
+      regions:          None,
+      regions_drawn:    false
+    };
+
+    match options.headless {
+      Some(ref output_dir) => unsafe {
+        render_headless(output_dir.as_slice(), keyframes, replay, &scene, &grid, &mut analysis, &window, &mut hud, bindings);
+      },
+      None => {}
+    }
+
     let mut last_time = glfw::get_time();
     let mut current_time: f64 = 0.0;
     let mut frames: u64 = 0;
 
-    while !window.should_close() {
+    let flythrough_start = glfw::get_time();
+    let mut previous_frame_time = glfw::get_time();
+
+    // Frames are counted separately from the FPS counter, for recording and replay
+    let mut frame_number: u64 = 0;
+    let mut replay_index = 0;
+
+    while options.headless.is_none() && !window.should_close() {
 
       // Compute FPS
       current_time = glfw::get_time();
       frames += 1;
 
       if current_time - last_time >= 1.0 {
-        println!("{} FPS ({} ms/frame)", frames, 1000.0/(frames as f64))
+        hud.fps = frames;
+        hud.frame_ms = 1000.0/(frames as f64);
         frames = 0;
         last_time += 1.0;
       }
 
-      // Poll events
+      // Poll events. Input is ignored while a replay is running so it cannot
+      // diverge, except for quitting.
       glfw::poll_events();
       for event in window.flush_events() {
-        handle_window_event(&window, event);
-        unsafe { update_uniforms() }
+        if replay_index < replay.len() && is_replayed_event(event) && !is_quit_event(bindings, event) { continue }
+
+        match recording {
+          Some(ref mut file) => record_event(file, frame_number, event),
+          None => {}
+        }
+
+        handle_window_event(&window, &mut hud, bindings, event);
+      }
+
+      while replay_index < replay.len() && replay[replay_index].frame <= frame_number {
+        let recorded = replay[replay_index];
+        match recorded.event {
+          glfw::SizeEvent(w, h) => window.set_size(w, h),
+          _ => {}
+        }
+        handle_window_event(&window, &mut hud, bindings, (recorded.time, recorded.event));
+        replay_index += 1;
+      }
+      frame_number += 1;
+
+      unsafe {
+        update_cursor_pick(&window, &grid);
+        update_analysis_overlays(&mut analysis, &scene, &grid);
+      }
+
+      // Labelled when contours are first shown and again after the interval changes
+      unsafe {
+        if contours_enabled && hud.labelled_interval != Some(contour_interval) {
+          hud.contour_labels = contour_labels(extract_contours(&grid, contour_interval));
+          hud.labelled_interval = Some(contour_interval);
+        }
+      }
+
+      // The water targets are resized here, since window events have no access to the scene
+      unsafe {
+        match framebuffer_resize.take() {
+          Some((w, h)) => resize_water_targets(&mut scene.water, w as u32, h as u32),
+          None => {}
+        }
+      }
+
+      // The observer is placed by a key action, which has no access to the grid
+      unsafe {
+        match observer_request.take() {
+          Some((x, y)) => {
+            let viewshed = compute_viewshed(&grid, (x, y), observer_height, view_range);
+            update_overlay_texture(scene.viewshed_texture_id, viewshed_pixels(&viewshed), &grid);
+            hud.status = format!("Observer at ({}, {}): {} cells visible", x, y, viewshed.visible);
+
+            match options.viewshed_png {
+              Some(ref file) => write_viewshed_mask(&grid, &viewshed, file.as_slice()),
+              None => {}
+            }
+          }
+          None => {}
+        }
+      }
+
+      unsafe {
+        if day_cycle { advance_time_of_day((current_time - previous_frame_time) as f32) }
       }
+      previous_frame_time = current_time;
 
-      // Clear the screen to black
-      gl::ClearColor(34.0/256.0, 37.0/256.0, 39.0/256.0, 1.0);
-      gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+      // Follow the flythrough path, if any, in real time
+      if keyframes.len() > 0 {
+        unsafe {
+          apply_keyframe(&sample_flythrough(keyframes, (current_time - flythrough_start) as f32));
+        }
+      }
 
       unsafe {
-        let kind = if draw_loops {gl::LINE_LOOP} else {gl::TRIANGLES};
-        gl::DrawElements(kind, indices.len() as GLint, gl::UNSIGNED_INT, ptr::null());
-        ticks += 1.0;
-        gl::Uniform1f(vs_data.ticks, ticks);
+        draw_frame(&scene);
+        if hud.visible { draw_hud(&text_renderer, &window, &hud, current_time) }
       }
 
       // Swap buffers
@@ -671,14 +2229,44 @@ fn main() {
     }
 
     // Cleanup
+    gl::DeleteProgram(text_renderer.program);
+    gl::DeleteProgram(scene.shadow_map.program);
     gl::DeleteProgram(shader_program);
-    gl::DeleteShader(fragment_shader);
-    gl::DeleteShader(vertex_shader);
 
     unsafe {
       gl::DeleteBuffers(1, &index_buffer_id);
       gl::DeleteBuffers(1, &vnt_buffer_id);
       gl::DeleteVertexArrays(1, &vertex_array_id);
+
+      gl::DeleteTextures(1, &text_renderer.font_texture_id);
+
+      gl::DeleteTextures(1, &scene.shadow_map.depth_texture_id);
+      gl::DeleteBuffers(1, &scene.camera_buffer_id);
+      gl::DeleteBuffers(1, &scene.light_buffer_id);
+      gl::DeleteTextures(1, &scene.lightmap_texture_id);
+      gl::DeleteTextures(1, &scene.height_texture_id);
+      gl::DeleteTextures(1, &scene.material_texture_id);
+      gl::DeleteTextures(1, &scene.land_use_texture_id);
+      gl::DeleteTextures(1, &scene.rivers_texture_id);
+      gl::DeleteTextures(1, &scene.regions_texture_id);
+      gl::DeleteTextures(1, &scene.viewshed_texture_id);
+
+      gl::DeleteProgram(scene.wireframe.program);
+      gl::DeleteBuffers(1, &scene.normal_lines.vertex_buffer_id);
+      gl::DeleteVertexArrays(1, &scene.normal_lines.vertex_array_id);
+      gl::DeleteProgram(scene.sky.program);
+      gl::DeleteVertexArrays(1, &scene.sky.vertex_array_id);
+
+      gl::DeleteProgram(scene.water.program);
+      gl::DeleteBuffers(1, &scene.water.index_buffer_id);
+      gl::DeleteBuffers(1, &scene.water.vertex_buffer_id);
+      gl::DeleteVertexArrays(1, &scene.water.vertex_array_id);
+      gl::DeleteTextures(1, &scene.water.ripple_map_texture_id);
+      delete_water_target(&scene.water.reflection);
+      delete_water_target(&scene.water.refraction);
+      gl::DeleteFramebuffers(1, &scene.shadow_map.framebuffer_id);
+      gl::DeleteBuffers(1, &text_renderer.vertex_buffer_id);
+      gl::DeleteVertexArrays(1, &text_renderer.vertex_array_id);
     }
   });
 }
@@ -690,222 +2278,3691 @@ impl glfw::ErrorCallback for ErrorContext {
     }
 }
 
-// OpenGL initializers  -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- --
+// Rendering  -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- --
 
-unsafe fn initialize_vbo<T>(vec: ~[T], buf_id: &mut GLuint, array_type: GLenum) {
-  let vec_bytes = (vec.len() * mem::size_of::<T>()) as GLsizeiptr;
-  let vec_ptr = cast::transmute(&vec[0]);
+unsafe fn draw_frame(scene: &Scene) {
 
-  gl::GenBuffers(1, buf_id);
-  gl::BindBuffer(array_type, *buf_id);
-  gl::BufferData(array_type, vec_bytes, vec_ptr, gl::STATIC_DRAW);
-}
+  // Every program reads the sun from the Light block
+  update_light_block(scene);
 
-unsafe fn initialize_world() {
+  // Render the shadow maps first, as that switches framebuffers
+  let light_matrices = if SHADOW_MAPS && shadows_enabled { draw_shadow_maps(scene) } else { ~[] };
 
-  let xr = deg(15.0f32).to_rad().s;
-  let yr = deg(0.0f32).to_rad().s;;
-  let zr = deg(-15.0f32).to_rad().s;
+  // Likewise the water reflection and refraction
+  if reflections_enabled { draw_water_targets(scene, light_matrices) }
 
-  world.rotation = Quat::new(1.0f32, xr, yr, zr); // Rotate the world
-  world.translation = Vec3::new(-1.0f32, -1.0f32, 0.0f32); // Center the world
-  world.scale = 0.001f32; // Zoom far out
+  // The sky covers the whole screen, so only depth needs clearing
+  gl::Clear(gl::DEPTH_BUFFER_BIT);
+  draw_sky(scene, &camera.view_matrix);
 
-  world.model_matrix = Transform3D::new(world.scale, world.rotation, world.translation).to_mat4();
+  // The terrain is not clipped here, as GL_CLIP_DISTANCE0 is disabled
+  draw_terrain(scene, &camera.view_matrix, &Vec4::new(0.0, 0.0, 0.0, 1.0), light_matrices);
+  ticks += 1.0;
+
+  draw_water(scene);
+
+  if wireframe_enabled { draw_wireframe(scene) }
+  if normal_lines_enabled { draw_normal_lines(scene) }
 }
 
-unsafe fn initialize_camera() {
+unsafe fn draw_terrain(scene: &Scene, view_matrix: &Mat4<f32>, clip_plane: &Vec4<f32>, light_matrices: &[Mat4<f32>]) {
+  gl::UseProgram(scene.program);
+  gl::BindVertexArray(scene.vertex_array_id);
 
-  camera.rotation = Quat::identity(); // No rotation
-  camera.translation = Vec3::zero(); // No translation
-  camera.scale = 1f32; // No zooming
+  // Uploaded every frame since the camera, flythrough and sun all change them.
+  // The view is replaced for the mirrored water reflection, and stays in the
+  // Camera block for the programs drawn after the terrain.
+  update_camera_block(scene, view_matrix);
+  vs_data.model_matrix.set(world.model_matrix);
+  vs_data.clip_plane.set(*clip_plane);
+  vs_data.flat_shading.set(flat_shading);
 
-  // camera.view_matrix = Transform3D::new(camera.scale, camera.rotation, camera.translation).to_mat4();
-  camera.view_matrix = Mat4::identity();
-}
+  gl::ActiveTexture(gl::TEXTURE3);
+  gl::BindTexture(gl::TEXTURE_2D, scene.lightmap_texture_id);
+  gl::ActiveTexture(gl::TEXTURE0);
 
-unsafe fn initialize_screen() {
-  screen.projection_matrix = Mat4::identity();
-}
+  fs_data.lightmap.set(3);
+  fs_data.lightmap_enabled.set(lightmap_enabled);
+
+  gl::BindTexture(gl::TEXTURE_2D_ARRAY, scene.material_texture_id);
+  fs_data.materials.set(0);
+  fs_data.triplanar_enabled.set(triplanar_enabled);
+
+  upload_fog(&fs_data.fog);
+
+  let show_land_use = land_use_enabled && scene.land_use_texture_id != 0;
+  fs_data.land_use_enabled.set(show_land_use);
+  if show_land_use {
+    gl::ActiveTexture(gl::TEXTURE8);
+    gl::BindTexture(gl::TEXTURE_2D, scene.land_use_texture_id);
+    gl::ActiveTexture(gl::TEXTURE0);
+
+    fs_data.land_use.set(8);
+    fs_data.land_use_opacity.set(LAND_USE_OPACITY);
+  }
+
+  fs_data.rivers_enabled.set(rivers_enabled);
+  if rivers_enabled {
+    gl::ActiveTexture(gl::TEXTURE9);
+    gl::BindTexture(gl::TEXTURE_2D, scene.rivers_texture_id);
+    gl::ActiveTexture(gl::TEXTURE0);
+
+    fs_data.rivers.set(9);
+  }
+
+  fs_data.regions_enabled.set(regions_enabled);
+  if regions_enabled {
+    gl::ActiveTexture(gl::TEXTURE10);
+    gl::BindTexture(gl::TEXTURE_2D, scene.regions_texture_id);
+    gl::ActiveTexture(gl::TEXTURE0);
+
+    fs_data.regions.set(10);
+  }
+
+  fs_data.viewshed_enabled.set(viewshed_enabled);
+  if viewshed_enabled {
+    gl::ActiveTexture(gl::TEXTURE11);
+    gl::BindTexture(gl::TEXTURE_2D, scene.viewshed_texture_id);
+    gl::ActiveTexture(gl::TEXTURE0);
+
+    fs_data.viewshed.set(11);
+  }
 
-unsafe fn update_uniforms() {
-  gl::UniformMatrix4fv(vs_data.projection_matrix, 1, gl::FALSE, screen.projection_matrix.cr(0,0));
-  gl::UniformMatrix4fv(vs_data.view_matrix, 1, gl::FALSE, camera.view_matrix.cr(0,0));
-  gl::UniformMatrix4fv(vs_data.model_matrix, 1, gl::FALSE, world.model_matrix.cr(0,0));
+  fs_data.shadows_enabled.set(shadows_enabled);
+  if SHADOW_MAPS && shadows_enabled {
+    gl::ActiveTexture(gl::TEXTURE2);
+    gl::BindTexture(gl::TEXTURE_2D_ARRAY, scene.shadow_map.depth_texture_id);
+    gl::ActiveTexture(gl::TEXTURE0);
 
-  gl::Uniform3f(fs_data.sunlight_color, world.sunlight.color.x, world.sunlight.color.y, world.sunlight.color.z);
-  gl::Uniform3f(fs_data.sunlight_direction, world.sunlight.direction.x, world.sunlight.direction.y, world.sunlight.direction.z);
-  gl::Uniform1f(fs_data.sunlight_intensity, world.sunlight.intensity);
+    fs_data.shadow_map.set(2);
+    fs_data.light_matrices.set_array(light_matrices);
+    fs_data.cascade_splits.set_array(cascade_splits());
+  }
+
+  fs_data.chunk_lines_enabled.set(chunk_lines_enabled);
+  fs_data.chunk_size.set(CHUNK_SIZE as f32);
+  fs_data.chunk_line_color.set(CHUNK_LINE_COLOR);
+
+  fs_data.contours_enabled.set(contours_enabled);
+  fs_data.contour_interval.set(contour_interval);
+  fs_data.contour_index_every.set(CONTOUR_INDEX_EVERY as i32);
+  fs_data.contour_color.set(CONTOUR_COLOR);
+
+  // The mesh has a single level of detail for now, so every chunk is at level 0
+  fs_data.debug_view.set(debug_view as i32);
+  fs_data.height_range.set(Vec2::new(-scene.terrain_max.z, -scene.terrain_min.z));
+  fs_data.lod_level.set(0);
+
+  gl::DrawElements(gl::TRIANGLES, scene.num_indices as GLint, gl::UNSIGNED_INT, ptr::null());
 }
 
-unsafe fn initialize_shader_data(shader_program: GLuint) {
-  vs_data.model_matrix       = "M".with_c_str(|ptr| gl::GetUniformLocation(shader_program, ptr));
-  vs_data.view_matrix        = "V".with_c_str(|ptr| gl::GetUniformLocation(shader_program, ptr));
-  vs_data.projection_matrix  = "P".with_c_str(|ptr| gl::GetUniformLocation(shader_program, ptr));
-  vs_data.ticks  = "timer".with_c_str(|ptr| gl::GetUniformLocation(shader_program, ptr));
+// Draws the edges of the terrain triangles over the shaded terrain. The lines
+// are pulled towards the camera so they win the depth test against their own faces.
+unsafe fn draw_wireframe(scene: &Scene) {
+  let wireframe = &scene.wireframe;
 
-  vs_data.scale              = "scaling".with_c_str(|ptr| gl::GetUniformLocation(shader_program, ptr));
-  vs_data.translation        = "translation".with_c_str(|ptr| gl::GetUniformLocation(shader_program, ptr));
-  fs_data.sunlight_color     = "sunlight.color".with_c_str(|ptr| gl::GetUniformLocation(shader_program, ptr));
-  fs_data.sunlight_direction = "sunlight.direction".with_c_str(|ptr| gl::GetUniformLocation(shader_program, ptr));
-  fs_data.sunlight_intensity = "sunlight.intensity".with_c_str(|ptr| gl::GetUniformLocation(shader_program, ptr));
+  gl::UseProgram(wireframe.program);
+  gl::BindVertexArray(scene.vertex_array_id);
 
-  "position".with_c_str(|ptr| gl::GetAttribLocation(shader_program, ptr));
-  "texcoord".with_c_str(|ptr| gl::GetAttribLocation(shader_program, ptr));
-  "normal".with_c_str(|ptr| gl::GetAttribLocation(shader_program, ptr));
+  wireframe.model_matrix.set(world.model_matrix);
+  wireframe.color.set(wireframe_color);
 
-  "out_color".with_c_str(|ptr| gl::BindFragDataLocation(shader_program, 0, ptr));
+  // Wide lines are optional in core profiles, so stay within what the driver supports
+  let mut width_range = [1.0 as GLfloat, 1.0];
+  gl::GetFloatv(gl::ALIASED_LINE_WIDTH_RANGE, width_range.as_mut_ptr());
+  gl::LineWidth(wireframe_width.max(width_range[0]).min(width_range[1]));
+
+  gl::PolygonMode(gl::FRONT_AND_BACK, gl::LINE);
+  gl::Enable(gl::POLYGON_OFFSET_LINE);
+  gl::PolygonOffset(-1.0, -1.0);
+  gl::Enable(gl::BLEND);
+
+  gl::DrawElements(gl::TRIANGLES, scene.num_indices as GLint, gl::UNSIGNED_INT, ptr::null());
+
+  gl::Disable(gl::BLEND);
+  gl::Disable(gl::POLYGON_OFFSET_LINE);
+  gl::PolygonMode(gl::FRONT_AND_BACK, gl::FILL);
+  gl::LineWidth(1.0);
 }
 
-// Event handling -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- --
+// Sky  -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- --
 
-unsafe fn update_model_matrix() {
-  world.model_matrix = Transform3D::new(world.scale, world.rotation, world.translation).to_mat4();
+// The sky near the horizon, which the fog also fades to. Haze brightens it
+// by day, following the ambient light.
+unsafe fn horizon_color() -> Vec3<f32> {
+  let haze = Vec3::new(0.85, 0.87, 0.9).mul_s(world.ambient.x * 4.0 + 0.3);
+  mix(world.sky_color, haze, 0.4)
 }
 
-unsafe fn update_view_matrix() {
-  camera.view_matrix = Transform3D::new(camera.scale, camera.rotation, camera.translation).to_mat4();
+unsafe fn current_fog_color() -> Vec3<f32> {
+  match fog_color {
+    Some(color) => color,
+    None => horizon_color()
+  }
 }
 
-unsafe fn update_projection_matrix() {
-  // screen.projection_matrix = Mat4::identity();
+fn fog_uniforms(uniforms: &UniformTable) -> FogUniforms {
+  FogUniforms {
+    enabled:        uniforms.uniform("fog_enabled"),
+    color:          uniforms.uniform("fog_color"),
+    density:        uniforms.uniform("fog_density"),
+    height_falloff: uniforms.uniform("fog_height_falloff"),
+    base:           uniforms.uniform("fog_base")
+  }
 }
 
-// Rotation  - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - -
+// Fog parameters shared by the terrain and water shaders
+unsafe fn upload_fog(fog: &FogUniforms) {
+  fog.enabled.set(fog_enabled);
+  fog.color.set(current_fog_color());
+  fog.density.set(fog_density);
+  fog.height_falloff.set(FOG_HEIGHT_FALLOFF);
+  fog.base.set(sea_level);
+}
 
-unsafe fn rotate_camera(x: f32, y: f32, z: f32) {
+// Draws the sky behind everything as seen with the given view
+unsafe fn draw_sky(scene: &Scene, view_matrix: &Mat4<f32>) {
+  let sky = &scene.sky;
 
-  camera.rotation.v.x += x;
-  camera.rotation.v.y += y;
-  camera.rotation.v.z += z;
+  let inverse_view_model = match view_matrix.mul_m(&world.model_matrix).invert() {
+    Some(m) => m,
+    None => return
+  };
 
-  update_view_matrix();
-}
+  let tan_half_fov = (deg(SKY_FIELD_OF_VIEW).to_rad().s * 0.5).tan();
+  let aspect = WINDOW_WIDTH as f32 / WINDOW_HEIGHT as f32;
+  let horizon = horizon_color();
 
-unsafe fn rotate_x(cw: bool) {
-  let sign = if cw {1.0} else {-1.0};
-  rotate_camera(CAMERA_ROTATE_BY * sign, 0.0, 0.0);
+  gl::UseProgram(sky.program);
+  gl::BindVertexArray(sky.vertex_array_id);
+
+  sky.inverse_view_model.set(inverse_view_model);
+  sky.view_extent.set(Vec2::new(tan_half_fov * aspect, tan_half_fov));
+  sky.sky_color.set(world.sky_color);
+  sky.horizon_color.set(horizon);
+  sky.sun_size.set(deg(SUN_ANGULAR_RADIUS).to_rad().s.cos());
+  sky.scattering_enabled.set(scattering_enabled);
+
+  gl::Disable(gl::DEPTH_TEST);
+  gl::DrawArrays(gl::TRIANGLES, 0, 3);
+  gl::Enable(gl::DEPTH_TEST);
 }
 
-unsafe fn rotate_y(cw: bool) {
-  let sign = if cw {1.0} else {-1.0};
-  rotate_camera(0.0, CAMERA_ROTATE_BY * sign, 0.0);
+// Reflects model space about the water plane. Elevation runs along -z, so the
+// plane is at z = -sea_level.
+fn mirror_matrix(level: f32) -> Mat4<f32> {
+  Mat4::new(1.0, 0.0,  0.0,          0.0,
+            0.0, 1.0,  0.0,          0.0,
+            0.0, 0.0, -1.0,          0.0,
+            0.0, 0.0, -2.0 * level,  1.0)
 }
 
-unsafe fn rotate_z(cw: bool) {
-  let sign = if cw {1.0} else {-1.0};
-  rotate_camera(0.0, 0.0, CAMERA_ROTATE_BY * sign);
+// Renders the terrain above the water as seen in the mirror, and the terrain
+// below it as seen by the camera, for the water shader to sample
+unsafe fn draw_water_targets(scene: &Scene, light_matrices: &[Mat4<f32>]) {
+  let water = &scene.water;
+
+  let model_inverse = match world.model_matrix.invert() {
+    Some(m) => m,
+    None => return
+  };
+  let reflected_view = camera.view_matrix.mul_m(&world.model_matrix)
+                                         .mul_m(&mirror_matrix(sea_level))
+                                         .mul_m(&model_inverse);
+
+  // Remember the current target, which is an offscreen framebuffer when headless
+  let mut previous_framebuffer: GLint = 0;
+  let mut previous_viewport = [0 as GLint, ..4];
+  gl::GetIntegerv(gl::FRAMEBUFFER_BINDING, &mut previous_framebuffer);
+  gl::GetIntegerv(gl::VIEWPORT, previous_viewport.as_mut_ptr());
+
+  gl::ClearColor(world.sky_color.x, world.sky_color.y, world.sky_color.z, 1.0);
+
+  // Mirroring flips the winding of every triangle
+  gl::BindFramebuffer(gl::FRAMEBUFFER, water.reflection.framebuffer_id);
+  gl::Viewport(0, 0, water.reflection.width as GLint, water.reflection.height as GLint);
+  gl::Clear(gl::DEPTH_BUFFER_BIT);
+  draw_sky(scene, &reflected_view);
+  gl::Enable(gl::CLIP_DISTANCE0);
+  gl::FrontFace(gl::CCW);
+  draw_terrain(scene, &reflected_view, &Vec4::new(0.0, 0.0, -1.0, CLIP_PLANE_OFFSET - sea_level), light_matrices);
+  gl::FrontFace(gl::CW);
+
+  gl::BindFramebuffer(gl::FRAMEBUFFER, water.refraction.framebuffer_id);
+  gl::Viewport(0, 0, water.refraction.width as GLint, water.refraction.height as GLint);
+  gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+  draw_terrain(scene, &camera.view_matrix, &Vec4::new(0.0, 0.0, 1.0, CLIP_PLANE_OFFSET + sea_level), light_matrices);
+
+  gl::Disable(gl::CLIP_DISTANCE0);
+
+  gl::BindFramebuffer(gl::FRAMEBUFFER, previous_framebuffer as GLuint);
+  gl::Viewport(previous_viewport[0], previous_viewport[1], previous_viewport[2], previous_viewport[3]);
 }
 
-// Translation - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - -
+// Draws the translucent water surface over the terrain. Depth writes are off
+// so the sea floor stays visible through it.
+unsafe fn draw_water(scene: &Scene) {
+  let water = &scene.water;
 
-unsafe fn translate_camera(x: f32, y: f32, z: f32) {
-  camera.translation.x += x;
-  camera.translation.y += y;
-  camera.translation.z += z;
+  gl::UseProgram(water.program);
+  gl::BindVertexArray(water.vertex_array_id);
 
-  update_view_matrix();
+  water.model_matrix.set(world.model_matrix);
+
+  water.timer.set(ticks);
+  water.sea_level.set(sea_level);
+
+  gl::ActiveTexture(gl::TEXTURE4);
+  gl::BindTexture(gl::TEXTURE_2D, scene.height_texture_id);
+  gl::ActiveTexture(gl::TEXTURE5);
+  gl::BindTexture(gl::TEXTURE_2D, water.reflection.color_texture_id);
+  gl::ActiveTexture(gl::TEXTURE6);
+  gl::BindTexture(gl::TEXTURE_2D, water.refraction.color_texture_id);
+  gl::ActiveTexture(gl::TEXTURE7);
+  gl::BindTexture(gl::TEXTURE_2D, water.ripple_map_texture_id);
+  gl::ActiveTexture(gl::TEXTURE0);
+
+  water.height_map.set(4);
+  water.reflection_map.set(5);
+  water.refraction_map.set(6);
+  water.normal_map.set(7);
+  water.reflections_enabled.set(reflections_enabled);
+
+  upload_fog(&water.fog);
+
+  gl::Disable(gl::CULL_FACE);
+  gl::Enable(gl::BLEND);
+  gl::DepthMask(gl::FALSE);
+
+  gl::DrawElements(gl::TRIANGLES, water.num_indices as GLint, gl::UNSIGNED_INT, ptr::null());
+
+  gl::DepthMask(gl::TRUE);
+  gl::Disable(gl::BLEND);
+  gl::Enable(gl::CULL_FACE);
 }
 
-unsafe fn move(dir: Compass) {
-  match dir {
-    North => translate_camera(0f32, -CAMERA_TRANSLATE_BY, 0f32),
-    South => translate_camera(0f32,  CAMERA_TRANSLATE_BY, 0f32),
-    West  => translate_camera( CAMERA_TRANSLATE_BY, 0f32, 0f32),
-    East  => translate_camera(-CAMERA_TRANSLATE_BY, 0f32, 0f32)
+// Renders the flythrough and/or input replay at a fixed frame rate into an
+// offscreen framebuffer, writing one PNG per frame. Replayed events are
+// dispatched on the frame they were recorded in, except that the frames keep
+// the window's size through replayed resizes. With neither, a single still is
+// written.
+unsafe fn render_headless(output_dir: &str, keyframes: &[Keyframe], replay: &[RecordedEvent], scene: &Scene,
+                          grid: &HeightGrid, analysis: &mut Analysis, window: &glfw::Window, hud: &mut Hud,
+                          bindings: &[KeyBinding]) {
+
+  let (framebuffer_id, color_buffer_id, depth_buffer_id) = initialize_offscreen_target(WINDOW_WIDTH, WINDOW_HEIGHT);
+
+  let flythrough_frames = if keyframes.len() > 0 {
+    (keyframes[keyframes.len() - 1].time * FLYTHROUGH_FPS) as uint + 1
+  } else {
+    1
+  };
+
+  let replay_frames = if replay.len() > 0 {
+    replay[replay.len() - 1].frame as uint + 1
+  } else {
+    1
+  };
+
+  let num_frames = if flythrough_frames > replay_frames {flythrough_frames} else {replay_frames};
+  let mut replay_index = 0;
+
+  for frame in range(0, num_frames) {
+    while replay_index < replay.len() && replay[replay_index].frame <= frame as u64 {
+      let recorded = replay[replay_index];
+      handle_window_event(window, hud, bindings, (recorded.time, recorded.event));
+      replay_index += 1;
+    }
+
+    if keyframes.len() > 0 {
+      apply_keyframe(&sample_flythrough(keyframes, frame as f32 / FLYTHROUGH_FPS));
+    }
+
+    // Advance the sun by a fixed step so renders are reproducible
+    if day_cycle { advance_time_of_day(1.0 / FLYTHROUGH_FPS) }
+
+    update_analysis_overlays(analysis, scene, grid);
+    draw_frame(scene);
+
+    let file_name = format!("{}/frame_{:05u}.png", output_dir, frame);
+    save_frame(file_name, WINDOW_WIDTH, WINDOW_HEIGHT);
+
+    if DEBUG { println!("Wrote {} ({}/{})", file_name, frame + 1, num_frames) }
   }
+
+  gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+  gl::DeleteRenderbuffers(1, &depth_buffer_id);
+  gl::DeleteRenderbuffers(1, &color_buffer_id);
+  gl::DeleteFramebuffers(1, &framebuffer_id);
 }
 
-// Scaling - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - -
+// Shadows  -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- --
 
-unsafe fn scale_camera(factor: f32) {
-  if camera.scale + factor > SCALE_MIN && camera.scale + factor < SCALE_MAX {
-    camera.scale += factor;
-  }
+fn transform_point(m: &Mat4<f32>, p: Vec3<f32>) -> Vec3<f32> {
+  let t = m.mul_v(&Vec4::new(p.x, p.y, p.z, 1.0));
+  Vec3::new(t.x / t.w, t.y / t.w, t.z / t.w)
+}
 
-  update_view_matrix();
+// Splits the camera's depth range into cascades, blending logarithmic and
+// uniform split distances. Returns the far end of each cascade as window
+// depth (0-1), which is what the fragment shader compares against.
+fn cascade_splits() -> ~[f32] {
+  let near = 0.05f32;
+
+  range(1, NUM_CASCADES + 1).map(|i| {
+    let f = i as f32 / NUM_CASCADES as f32;
+    let logarithmic = near * (1.0 / near).powf(f);
+    CASCADE_SPLIT_LAMBDA * logarithmic + (1.0 - CASCADE_SPLIT_LAMBDA) * f
+  }).collect()
 }
 
-unsafe fn zoom(dir: Zoom) {
-  match dir {
-    In  => scale_camera( CAMERA_SCALE_BY),
-    Out => scale_camera(-CAMERA_SCALE_BY)
+// Fits an orthographic light projection around the slice of the view volume
+// between two window depths. The light's depth range is extended to cover the
+// whole terrain, so mountains outside the slice still cast into it.
+unsafe fn cascade_light_matrix(scene: &Scene, near_depth: f32, far_depth: f32) -> Mat4<f32> {
+  let inverse_view_projection = match screen.projection_matrix.mul_m(&camera.view_matrix).invert() {
+    Some(m) => m,
+    None => return Mat4::identity()
+  };
+
+  let mut corners: ~[Vec3<f32>] = ~[];
+  for &x in [-1.0f32, 1.0].iter() {
+    for &y in [-1.0f32, 1.0].iter() {
+      for &depth in [near_depth, far_depth].iter() {
+        corners.push(transform_point(&inverse_view_projection, Vec3::new(x, y, depth * 2.0 - 1.0)));
+      }
+    }
   }
-}
 
-unsafe fn adjust_light_intensity(factor: f32) {
-  if world.sunlight.intensity + factor > SUNLIGHT_INTENSITY_MIN
-  && world.sunlight.intensity + factor < SUNLIGHT_INTENSITY_MAX {
-    world.sunlight.intensity += factor
+  let center = corners.iter().fold(Vec3::zero(), |sum, &c| sum + c).div_s(corners.len() as f32);
+
+  // The sun's direction has elevation as z, but elevation is drawn towards -z
+  // (see test.vert), and the shaders light the terrain in model space
+  let sun = world.sunlight.direction;
+  let d = world.model_matrix.mul_v(&Vec4::new(sun.x, sun.y, -sun.z, 0.0));
+  let light_direction = Vec3::new(d.x, d.y, d.z).normalize();
+  let up = if light_direction.y.abs() > 0.99 { Vec3::new(1.0f32, 0.0, 0.0) } else { Vec3::new(0.0f32, 1.0, 0.0) };
+  let eye = center + light_direction;
+
+  let light_view = Mat4::look_at(&Point3::new(eye.x, eye.y, eye.z), &Point3::new(center.x, center.y, center.z), &up);
+
+  let mut lo = Vec3::new(std::f32::INFINITY, std::f32::INFINITY, std::f32::INFINITY);
+  let mut hi = Vec3::new(-std::f32::INFINITY, -std::f32::INFINITY, -std::f32::INFINITY);
+
+  for &corner in corners.iter() {
+    let p = transform_point(&light_view, corner);
+    lo = Vec3::new(lo.x.min(p.x), lo.y.min(p.y), lo.z.min(p.z));
+    hi = Vec3::new(hi.x.max(p.x), hi.y.max(p.y), hi.z.max(p.z));
   }
-}
 
-fn handle_window_event(window: &glfw::Window, (time, event): (f64, glfw::WindowEvent)) {
-  unsafe {
-    match event {
-      glfw::PosEvent(x, y)                => window.set_title(format!("Time: {}, Window pos: ({}, {})", time, x, y)),
-      glfw::SizeEvent(w, h)               => window.set_title(format!("Time: {}, Window size: ({}, {})", time, w, h)),
-      glfw::CloseEvent                    => println!("Time: {}, Window close requested.", time),
-      glfw::RefreshEvent                  => println!("Time: {}, Window refresh callback triggered.", time),
-      glfw::FocusEvent(true)              => println!("Time: {}, Window focus gained.", time),
-      glfw::FocusEvent(false)             => println!("Time: {}, Window focus lost.", time),
-      glfw::IconifyEvent(true)            => println!("Time: {}, Window was minimised", time),
-      glfw::IconifyEvent(false)           => println!("Time: {}, Window was maximised.", time),
-      glfw::FramebufferSizeEvent(w, h)    => println!("Time: {}, Framebuffer size: ({}, {})", time, w, h),
-      glfw::CharEvent(character)          => println!("Time: {}, Character: {}", time, character),
-      glfw::MouseButtonEvent(btn, action, mods) => println!("Time: {}, Button: {}, Action: {}, Modifiers: [{}]", time, btn, action, mods),
-      glfw::CursorPosEvent(xpos, ypos)    => window.set_title(format!("Time: {}, Cursor position: ({}, {})", time, xpos, ypos)),
-      glfw::CursorEnterEvent(true)        => println!("Time: {}, Cursor entered window.", time),
-      glfw::CursorEnterEvent(false)       => println!("Time: {}, Cursor left window.", time),
-      glfw::ScrollEvent(x, y)             => window.set_title(format!("Time: {}, Scroll offset: ({}, {})", time, x, y)),
-      glfw::KeyEvent(key, scancode, action, mods) => {
-        println!("Time: {}, Key: {}, ScanCode: {}, Action: {}, Modifiers: [{}]", time, key, scancode, action, mods);
-        handle_key_event(window, key, action, mods);
+  for &x in [scene.terrain_min.x, scene.terrain_max.x].iter() {
+    for &y in [scene.terrain_min.y, scene.terrain_max.y].iter() {
+      for &z in [scene.terrain_min.z, scene.terrain_max.z].iter() {
+        let p = transform_point(&light_view, transform_point(&world.model_matrix, Vec3::new(x, y, z)));
+        lo.z = lo.z.min(p.z);
+        hi.z = hi.z.max(p.z);
       }
     }
   }
+
+  // The light looks down -z, so near and far are negated view space depths
+  ortho(lo.x, hi.x, lo.y, hi.y, -hi.z, -lo.z).mul_m(&light_view)
+}
+
+// Renders the terrain's depth from the sun into each cascade and returns the
+// light matrices (light projection * light view) used for each of them
+unsafe fn draw_shadow_maps(scene: &Scene) -> ~[Mat4<f32>] {
+  let shadow_map = &scene.shadow_map;
+  let splits = cascade_splits();
+
+  // Remember the current target, which is an offscreen framebuffer when headless
+  let mut previous_framebuffer: GLint = 0;
+  let mut previous_viewport = [0 as GLint, ..4];
+  gl::GetIntegerv(gl::FRAMEBUFFER_BINDING, &mut previous_framebuffer);
+  gl::GetIntegerv(gl::VIEWPORT, previous_viewport.as_mut_ptr());
+
+  gl::BindFramebuffer(gl::FRAMEBUFFER, shadow_map.framebuffer_id);
+  gl::Viewport(0, 0, SHADOW_MAP_SIZE as GLint, SHADOW_MAP_SIZE as GLint);
+
+  gl::UseProgram(shadow_map.program);
+  gl::BindVertexArray(scene.vertex_array_id);
+
+  // Both sides of the terrain may face the sun, and offsetting depth reduces acne
+  gl::Disable(gl::CULL_FACE);
+  gl::Enable(gl::POLYGON_OFFSET_FILL);
+  gl::PolygonOffset(2.0, 4.0);
+
+  let mut light_matrices: ~[Mat4<f32>] = ~[];
+
+  for cascade in range(0, NUM_CASCADES) {
+    let near_depth = if cascade == 0 { 0.0 } else { splits[cascade - 1] };
+    let light_matrix = cascade_light_matrix(scene, near_depth, splits[cascade]);
+    let light_model_matrix = light_matrix.mul_m(&world.model_matrix);
+
+    gl::FramebufferTextureLayer(gl::FRAMEBUFFER, gl::DEPTH_ATTACHMENT, shadow_map.depth_texture_id, 0, cascade as GLint);
+    gl::Clear(gl::DEPTH_BUFFER_BIT);
+
+    shadow_map.light_matrix.set(light_model_matrix);
+    gl::DrawElements(gl::TRIANGLES, scene.num_indices as GLint, gl::UNSIGNED_INT, ptr::null());
+
+    light_matrices.push(light_matrix);
+  }
+
+  gl::Disable(gl::POLYGON_OFFSET_FILL);
+  gl::Enable(gl::CULL_FACE);
+
+  gl::BindFramebuffer(gl::FRAMEBUFFER, previous_framebuffer as GLuint);
+  gl::Viewport(previous_viewport[0], previous_viewport[1], previous_viewport[2], previous_viewport[3]);
+
+  light_matrices
+}
+
+// Reads back the current framebuffer and stores it as an RGB PNG
+unsafe fn save_frame(file_path: &str, width: u32, height: u32) {
+  let row_bytes = (width * 3) as uint;
+  let mut pixels = vec::from_elem(row_bytes * height as uint, 0u8);
+
+  gl::PixelStorei(gl::PACK_ALIGNMENT, 1);
+  gl::ReadPixels(0, 0, width as GLint, height as GLint, gl::RGB, gl::UNSIGNED_BYTE, pixels.as_mut_ptr() as *mut GLvoid);
+
+  // OpenGL stores rows bottom-up, PNG top-down
+  let mut flipped: ~[u8] = ~[];
+  for row in range(0, height as uint).rev() {
+    flipped.push_all(pixels.slice(row * row_bytes, (row + 1) * row_bytes));
+  }
+
+  let image = png::Image { width: width, height: height, color_type: png::RGB8, pixels: flipped };
+  match png::store_png(&image, &Path::new(file_path)) {
+    Ok(()) => {},
+    Err(s) => fail!(s)
+  }
 }
 
-unsafe fn handle_key_event(window: &glfw::Window, key: glfw::Key, action: glfw::Action, mods: glfw::Modifiers) {
+// HUD  -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- --
+
+// Builds two textured triangles per character, starting at (x, y) in pixels
+fn layout_text(text: &str, x: f32, y: f32) -> ~[TextVertex] {
+  let mut vertices: ~[TextVertex] = ~[];
 
-  match (key, action) {
-    (glfw::KeyEscape, glfw::Press) => window.set_should_close(true),
-    (glfw::KeyW, _) => { move(North) },
-    (glfw::KeyS, _) => { move(South) },
-    (glfw::KeyA, _) => { move(West)  },
-    (glfw::KeyD, _) => { move(East)  },
+  let glyph_width = FONT_GLYPH_WIDTH as f32;
+  let glyph_height = FONT_GLYPH_HEIGHT as f32;
+  let atlas_width = (FONT_GLYPH_WIDTH * FONT_COLUMNS) as f32;
+  let atlas_height = (FONT_GLYPH_HEIGHT * FONT_ROWS) as f32;
 
-    // Rotate X or Z
-    (glfw::KeyUp, _)  => {
-      if mods.contains(glfw::Shift) { rotate_z(true) } else { rotate_x(true) }
+  let mut pen_x = x;
+  let mut pen_y = y;
+
+  for c in text.chars() {
+    if c == '\n' {
+      pen_x = x;
+      pen_y += glyph_height;
+      continue
     }
 
-    (glfw::KeyDown, _)  => {
-      if mods.contains(glfw::Shift) { rotate_z(false) } else { rotate_x(false) }
+    let code = c as u32;
+    let glyph = if code >= 32 && code < 128 {code - 32} else {'?' as u32 - 32};
+
+    if c != ' ' {
+      let u0 = ((glyph % FONT_COLUMNS) * FONT_GLYPH_WIDTH) as f32 / atlas_width;
+      let v0 = ((glyph / FONT_COLUMNS) * FONT_GLYPH_HEIGHT) as f32 / atlas_height;
+      let u1 = u0 + glyph_width / atlas_width;
+      let v1 = v0 + glyph_height / atlas_height;
+
+      let x0 = pen_x;
+      let y0 = pen_y;
+      let x1 = pen_x + glyph_width;
+      let y1 = pen_y + glyph_height;
+
+      vertices.push_all(&[
+        TextVertex { position: Vec2::new(x0, y0), texcoord: Vec2::new(u0, v0) },
+        TextVertex { position: Vec2::new(x0, y1), texcoord: Vec2::new(u0, v1) },
+        TextVertex { position: Vec2::new(x1, y0), texcoord: Vec2::new(u1, v0) },
+        TextVertex { position: Vec2::new(x1, y0), texcoord: Vec2::new(u1, v0) },
+        TextVertex { position: Vec2::new(x0, y1), texcoord: Vec2::new(u0, v1) },
+        TextVertex { position: Vec2::new(x1, y1), texcoord: Vec2::new(u1, v1) }
+      ]);
     }
+    pen_x += glyph_width;
+  }
+  vertices
+}
 
-    // Rotate Y
-    (glfw::KeyLeft, _)   => { rotate_y(true) },
-    (glfw::KeyRight, _)  => { rotate_y(false) },
+unsafe fn draw_text(text_renderer: &TextRenderer, text: &str, x: f32, y: f32, color: Vec4<f32>) {
+  let vertices = layout_text(text, x, y);
+  if vertices.len() == 0 { return }
 
-    (glfw::KeyR, glfw::Press)      => { zoom(In) },
-    (glfw::KeyR, glfw::Repeat)     => { zoom(In) },
-    (glfw::KeyF, glfw::Press)      => { zoom(Out) },
-    (glfw::KeyF, glfw::Repeat)     => { zoom(Out) },
+  let vertices_bytes = (vertices.len() * mem::size_of::<TextVertex>()) as GLsizeiptr;
 
-    (glfw::KeyK, glfw::Press)      => { adjust_light_intensity(-0.02) },
-    (glfw::KeyL, glfw::Press)      => { adjust_light_intensity(0.02) },
-    (glfw::KeyK, glfw::Repeat)     => { adjust_light_intensity(-0.02) },
-    (glfw::KeyL, glfw::Repeat)     => { adjust_light_intensity(0.02) },
+  gl::BindBuffer(gl::ARRAY_BUFFER, text_renderer.vertex_buffer_id);
+  gl::BufferData(gl::ARRAY_BUFFER, vertices_bytes, cast::transmute(&vertices[0]), gl::STREAM_DRAW);
 
-    (glfw::KeyT, glfw::Press)      => { draw_loops = !draw_loops },
+  text_renderer.color.set(color);
+  gl::DrawArrays(gl::TRIANGLES, 0, vertices.len() as GLint);
+}
 
-    (glfw::KeySpace, glfw::Press) => {
-      // Resize should cause the window to "refresh"
-      let (window_width, window_height) = window.get_size();
-      window.set_size(window_width + 1, window_height);
-      window.set_size(window_width, window_height);
-    }
-    _ => {}
+// Overlays currently shown on top of the terrain
+unsafe fn active_layers(hud: &Hud) -> ~str {
+  let mut layers = ~"terrain";
+  if SHADOW_MAPS && shadows_enabled { layers = layers + ", shadows" }
+  if lightmap_enabled { layers = layers + ", lightmap" }
+  layers = layers + format!(", water (sea level {:.1f})", sea_level);
+  if reflections_enabled { layers = layers + ", reflections" }
+  if triplanar_enabled { layers = layers + ", triplanar" }
+  // Like draw_terrain, only with a land use map; hud.land_use is empty without one
+  if land_use_enabled && hud.land_use.len() > 0 { layers = layers + ", land use" }
+  if rivers_enabled { layers = layers + ", rivers" }
+  if regions_enabled { layers = layers + ", regions" }
+  if viewshed_enabled {
+    layers = layers + format!(", viewshed (eye {:.1f}, range {:.0f})", observer_height, view_range)
+  }
+  if fog_enabled { layers = layers + format!(", fog (density {:.2f})", fog_density) }
+  if scattering_enabled { layers = layers + ", scattering" }
+  if wireframe_enabled { layers = layers + ", wireframe" }
+  if chunk_lines_enabled { layers = layers + format!(", chunks ({} cells)", CHUNK_SIZE) }
+  if contours_enabled { layers = layers + format!(", contours (every {})", contour_interval) }
+  if normal_lines_enabled { layers = layers + ", normal lines" }
+  if flat_shading { layers = layers + ", flat shading" }
+  match debug_view {
+    NoDebugView  => {}
+    NormalView   => layers = layers + ", debug: normals",
+    TexcoordView => layers = layers + ", debug: texcoords",
+    HeightView   => layers = layers + ", debug: height",
+    LodView      => layers = layers + ", debug: LOD"
+  }
+  layers
+}
+
+unsafe fn hud_text(hud: &Hud, time: f64) -> ~str {
+  let cursor = match cursor_pick {
+    Some(pick) => {
+      let (cx, cy) = pick.cell;
+      format!("cell ({}, {}), elevation {:.2f}", cx, cy, pick.elevation)
+    }
+    None => ~"no terrain"
+  };
+
+  format!("{} FPS ({:.2f} ms/frame)\n\
+           Camera: translation ({:.3f}, {:.3f}, {:.3f}), rotation ({:.3f}, {:.3f}, {:.3f}, {:.3f}), scale {:.2f}\n\
+           Cursor: {}\n\
+           Layers: {}\n\
+           Time: {:.1f} s, frame {}\n\
+           Simulation: {}\n\
+           {}",
+    hud.fps, hud.frame_ms,
+    camera.translation.x, camera.translation.y, camera.translation.z,
+    camera.rotation.s, camera.rotation.v.x, camera.rotation.v.y, camera.rotation.v.z, camera.scale,
+    cursor,
+    active_layers(hud),
+    time, ticks, simulation_time_text(),
+    hud.status)
+}
+
+unsafe fn draw_hud(text_renderer: &TextRenderer, window: &glfw::Window, hud: &Hud, time: f64) {
+  let (window_width, window_height) = window.get_size();
+  let text = hud_text(hud, time);
+
+  gl::UseProgram(text_renderer.program);
+  gl::BindVertexArray(text_renderer.vertex_array_id);
+
+  gl::ActiveTexture(gl::TEXTURE1);
+  gl::BindTexture(gl::TEXTURE_2D, text_renderer.font_texture_id);
+  text_renderer.font.set(1);
+  text_renderer.screen_size.set(Vec2::new(window_width as f32, window_height as f32));
+
+  gl::Disable(gl::DEPTH_TEST);
+  gl::Disable(gl::CULL_FACE);
+  gl::Enable(gl::BLEND);
+
+  // Draw a drop shadow first so the text stays legible over snow and water
+  draw_text(text_renderer, text, 11.0, 11.0, Vec4::new(0.0, 0.0, 0.0, 0.8));
+  draw_text(text_renderer, text, 10.0, 10.0, Vec4::new(1.0, 1.0, 1.0, 1.0));
+
+  if land_use_enabled && hud.land_use.len() > 0 {
+    draw_land_use_legend(text_renderer, hud, window_height as f32);
+  }
+
+  if contours_enabled {
+    draw_contour_labels(text_renderer, hud, window_width as f32, window_height as f32);
+  }
+
+  if hud.show_help {
+    let x = window_width as f32 - 48.0 * FONT_GLYPH_WIDTH as f32;
+    draw_text(text_renderer, hud.help, x + 1.0, 11.0, Vec4::new(0.0, 0.0, 0.0, 0.8));
+    draw_text(text_renderer, hud.help, x, 10.0, Vec4::new(1.0, 1.0, 0.6, 1.0));
+  }
+
+  gl::Disable(gl::BLEND);
+  gl::Enable(gl::CULL_FACE);
+  gl::Enable(gl::DEPTH_TEST);
+  gl::ActiveTexture(gl::TEXTURE0);
+}
+
+// Lists the land use classes in the bottom left corner, each after a swatch of its color
+unsafe fn draw_land_use_legend(text_renderer: &TextRenderer, hud: &Hud, window_height: f32) {
+  let total = hud.land_use.iter().fold(0, |a, &b| a + b);
+  let line_height = FONT_GLYPH_HEIGHT as f32;
+  let x = 10.0;
+  let mut y = window_height - 10.0 - line_height * (LAND_USE_CLASSES.len() + 1) as f32;
+
+  draw_text(text_renderer, "Land use", x + 1.0, y + 1.0, Vec4::new(0.0, 0.0, 0.0, 0.8));
+  draw_text(text_renderer, "Land use", x, y, Vec4::new(1.0, 1.0, 1.0, 1.0));
+
+  for (class, &count) in LAND_USE_CLASSES.iter().zip(hud.land_use.iter()) {
+    y += line_height;
+
+    let share = if total > 0 { 100.0 * count as f32 / total as f32 } else { 0.0 };
+    let label = format!("   {} ({:.1f}%)", class.name, share);
+    let color = Vec4::new(class.color[0] as f32 / 255.0, class.color[1] as f32 / 255.0, class.color[2] as f32 / 255.0, 1.0);
+
+    draw_text(text_renderer, "##", x, y, color);
+    draw_text(text_renderer, label, x + 1.0, y + 1.0, Vec4::new(0.0, 0.0, 0.0, 0.8));
+    draw_text(text_renderer, label, x, y, Vec4::new(1.0, 1.0, 1.0, 1.0));
+  }
+}
+
+// Centers each index contour label on its point, if that is on screen. Labels
+// are not hidden behind the terrain in front of them.
+unsafe fn draw_contour_labels(text_renderer: &TextRenderer, hud: &Hud, window_width: f32, window_height: f32) {
+  let mvp = screen.projection_matrix.mul_m(&camera.view_matrix).mul_m(&world.model_matrix);
+  let color = Vec4::new(CONTOUR_COLOR.x, CONTOUR_COLOR.y, CONTOUR_COLOR.z, 1.0);
+
+  for label in hud.contour_labels.iter() {
+    let p = mvp.mul_v(&Vec4::new(label.position.x, label.position.y, label.position.z, 1.0));
+    if p.w <= 0.0 { continue }
+
+    let (ndc_x, ndc_y) = (p.x / p.w, p.y / p.w);
+    if ndc_x.abs() > 1.0 || ndc_y.abs() > 1.0 { continue }
+
+    let x = (ndc_x + 1.0) * 0.5 * window_width - (label.text.len() * FONT_GLYPH_WIDTH as uint) as f32 * 0.5;
+    let y = (1.0 - ndc_y) * 0.5 * window_height - FONT_GLYPH_HEIGHT as f32 * 0.5;
+
+    draw_text(text_renderer, label.text, x + 1.0, y + 1.0, Vec4::new(1.0, 1.0, 1.0, 0.7));
+    draw_text(text_renderer, label.text, x, y, color);
+  }
+}
+
+// OpenGL initializers  -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- --
+
+unsafe fn initialize_shadow_map(vs_src: &ShaderSource, fs_src: &ShaderSource) -> ShadowMap {
+  let program = build_program(&[(gl::VERTEX_SHADER, vs_src), (gl::FRAGMENT_SHADER, fs_src)]);
+
+  let mut framebuffer_id = 0;
+  let mut depth_texture_id = 0;
+
+  gl::GenTextures(1, &mut depth_texture_id);
+  gl::BindTexture(gl::TEXTURE_2D_ARRAY, depth_texture_id);
+  gl::TexImage3D(gl::TEXTURE_2D_ARRAY, 0, gl::DEPTH_COMPONENT24 as GLint,
+                 SHADOW_MAP_SIZE as GLint, SHADOW_MAP_SIZE as GLint, NUM_CASCADES as GLint,
+                 0, gl::DEPTH_COMPONENT, gl::FLOAT, ptr::null());
+
+  // Linear filtering with depth comparison gives 2x2 PCF in hardware for free
+  gl::TexParameteri(gl::TEXTURE_2D_ARRAY, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+  gl::TexParameteri(gl::TEXTURE_2D_ARRAY, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
+  gl::TexParameteri(gl::TEXTURE_2D_ARRAY, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
+  gl::TexParameteri(gl::TEXTURE_2D_ARRAY, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
+  gl::TexParameteri(gl::TEXTURE_2D_ARRAY, gl::TEXTURE_COMPARE_MODE, gl::COMPARE_REF_TO_TEXTURE as i32);
+  gl::TexParameteri(gl::TEXTURE_2D_ARRAY, gl::TEXTURE_COMPARE_FUNC, gl::LEQUAL as i32);
+
+  gl::GenFramebuffers(1, &mut framebuffer_id);
+  gl::BindFramebuffer(gl::FRAMEBUFFER, framebuffer_id);
+  gl::FramebufferTextureLayer(gl::FRAMEBUFFER, gl::DEPTH_ATTACHMENT, depth_texture_id, 0, 0);
+  gl::DrawBuffer(gl::NONE);
+  gl::ReadBuffer(gl::NONE);
+
+  if gl::CheckFramebufferStatus(gl::FRAMEBUFFER) != gl::FRAMEBUFFER_COMPLETE {
+    fail!("Shadow map framebuffer is incomplete");
+  }
+  gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+
+  ShadowMap {
+    program:          program,
+    framebuffer_id:   framebuffer_id,
+    depth_texture_id: depth_texture_id,
+
+    light_matrix: UniformTable::reflect(program, "shadow").uniform("light_matrix")
+  }
+}
+
+// The lightmap is stored in the same order as the heights, so grid x runs
+// down the texture rows and grid y along them
+unsafe fn initialize_lightmap_texture(lightmap: &[u8], grid: &HeightGrid) -> GLuint {
+  let mut texture_id = 0;
+
+  gl::GenTextures(1, &mut texture_id);
+  gl::BindTexture(gl::TEXTURE_2D, texture_id);
+  gl::PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+  gl::TexImage2D(gl::TEXTURE_2D, 0, gl::RGB8 as GLint, grid.height as GLint, grid.width as GLint, 0, gl::RGB, gl::UNSIGNED_BYTE, lightmap.as_ptr() as GLeglImageOES);
+  gl::PixelStorei(gl::UNPACK_ALIGNMENT, 4);
+
+  gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+  gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
+  gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
+  gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
+
+  texture_id
+}
+
+// Terrain heights as a float texture, laid out like the lightmap
+unsafe fn initialize_height_texture(grid: &HeightGrid) -> GLuint {
+  let mut texture_id = 0;
+
+  gl::GenTextures(1, &mut texture_id);
+  gl::BindTexture(gl::TEXTURE_2D, texture_id);
+  gl::TexImage2D(gl::TEXTURE_2D, 0, gl::R32F as GLint, grid.height as GLint, grid.width as GLint, 0, gl::RED, gl::FLOAT, grid.heights.as_ptr() as GLeglImageOES);
+
+  gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+  gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
+  gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
+  gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
+
+  texture_id
+}
+
+// Builds a flat grid of WATER_RESOLUTION quads per side spanning the terrain
+unsafe fn initialize_water(vs_src: &ShaderSource, fs_src: &ShaderSource, grid: &HeightGrid) -> Water {
+  let program = build_program(&[(gl::VERTEX_SHADER, vs_src), (gl::FRAGMENT_SHADER, fs_src)]);
+
+  let n = WATER_RESOLUTION + 1;
+  let step_x = (grid.width - 1) as f32 / WATER_RESOLUTION as f32;
+  let step_y = (grid.height - 1) as f32 / WATER_RESOLUTION as f32;
+
+  let mut vertices: ~[Vec2<GLfloat>] = ~[];
+  for x in range(0, n) {
+    for y in range(0, n) {
+      vertices.push(Vec2::new(x as f32 * step_x, y as f32 * step_y));
+    }
+  }
+
+  let mut indices: ~[u32] = ~[];
+  for x in range(0, n - 1) {
+    for y in range(0, n - 1) {
+      let start = x * n + y;
+      indices.push_all(&[start, start + 1, start + n, start + 1, start + 1 + n, start + n]);
+    }
+  }
+
+  let mut vertex_array_id = 0;
+  let mut vertex_buffer_id = 0;
+  let mut index_buffer_id = 0;
+  let num_indices = indices.len();
+
+  gl::GenVertexArrays(1, &mut vertex_array_id);
+  gl::BindVertexArray(vertex_array_id);
+
+  initialize_vbo(vertices, &mut vertex_buffer_id, gl::ARRAY_BUFFER);
+  initialize_vbo(indices, &mut index_buffer_id, gl::ELEMENT_ARRAY_BUFFER);
+
+  initialize_vertex_layout::<Vec2<GLfloat>>(program);
+
+  let uniforms = UniformTable::reflect(program, "water");
+
+  let target_width = WINDOW_WIDTH / WATER_TARGET_DIVISOR;
+  let target_height = WINDOW_HEIGHT / WATER_TARGET_DIVISOR;
+
+  let water = Water {
+    program:          program,
+    vertex_array_id:  vertex_array_id,
+    vertex_buffer_id: vertex_buffer_id,
+    index_buffer_id:  index_buffer_id,
+    num_indices:      num_indices,
+
+    reflection:            initialize_water_target(target_width, target_height),
+    refraction:            initialize_water_target(target_width, target_height),
+    ripple_map_texture_id: initialize_ripple_map(RIPPLE_MAP_SIZE),
+
+    model_matrix:       uniforms.uniform("M"),
+    timer:              uniforms.uniform("timer"),
+    sea_level:          uniforms.uniform("sea_level"),
+    height_map:         uniforms.uniform("height_map"),
+
+    reflections_enabled: uniforms.uniform("reflections_enabled"),
+    reflection_map:      uniforms.uniform("reflection_map"),
+    refraction_map:      uniforms.uniform("refraction_map"),
+    normal_map:          uniforms.uniform("normal_map"),
+
+    fog: fog_uniforms(&uniforms),
+
+    wave_directions: uniforms.uniform("wave_directions"),
+    wave_lengths:    uniforms.uniform("wave_lengths"),
+    wave_amplitudes: uniforms.uniform("wave_amplitudes"),
+    wave_steepness:  uniforms.uniform("wave_steepness"),
+    wave_speeds:     uniforms.uniform("wave_speeds")
+  };
+
+  // The waves never change, so upload them once. Their number is NUM_WAVES in the shader.
+  let directions: ~[Vec2<f32>] = WAVES.iter().map(|w| w.direction).collect();
+  let lengths: ~[f32] = WAVES.iter().map(|w| w.wavelength).collect();
+  let amplitudes: ~[f32] = WAVES.iter().map(|w| w.amplitude).collect();
+  let steepness: ~[f32] = WAVES.iter().map(|w| w.steepness).collect();
+  let speeds: ~[f32] = WAVES.iter().map(|w| w.speed).collect();
+
+  gl::UseProgram(program);
+  water.wave_directions.set_array(directions);
+  water.wave_lengths.set_array(lengths);
+  water.wave_amplitudes.set_array(amplitudes);
+  water.wave_steepness.set_array(steepness);
+  water.wave_speeds.set_array(speeds);
+
+  water
+}
+
+unsafe fn draw_normal_lines(scene: &Scene) {
+  let wireframe = &scene.wireframe;
+
+  gl::UseProgram(wireframe.program);
+  gl::BindVertexArray(scene.normal_lines.vertex_array_id);
+
+  wireframe.model_matrix.set(world.model_matrix);
+  wireframe.color.set(NORMAL_LINE_COLOR);
+
+  gl::DrawArrays(gl::LINES, 0, scene.normal_lines.num_vertices as GLint);
+}
+
+unsafe fn initialize_wireframe(vs_src: &ShaderSource, fs_src: &ShaderSource) -> Wireframe {
+  let program = build_program(&[(gl::VERTEX_SHADER, vs_src), (gl::FRAGMENT_SHADER, fs_src)]);
+
+  let uniforms = UniformTable::reflect(program, "wireframe");
+
+  Wireframe {
+    program: program,
+
+    model_matrix:      uniforms.uniform("M"),
+    color:             uniforms.uniform("color")
+  }
+}
+
+// Normals are stored pointing into the ground (see initialize_normals), so the
+// lines follow them negated, out of the surface
+unsafe fn initialize_normal_lines(program: GLuint, vertices: &[Vec3<GLfloat>], normals: &[Vec3<GLfloat>], width: u32, height: u32) -> NormalLines {
+  let mut lines: ~[Vec3<GLfloat>] = ~[];
+
+  for row in range_step(0, width, NORMAL_LINE_SPACING) {
+    for col in range_step(0, height, NORMAL_LINE_SPACING) {
+      let i = (width * row + col) as uint;
+      lines.push(vertices[i]);
+      lines.push(vertices[i] - normals[i].mul_s(NORMAL_LINE_LENGTH));
+    }
+  }
+
+  let mut vertex_array_id = 0;
+  let mut vertex_buffer_id = 0;
+  let num_vertices = lines.len();
+
+  gl::GenVertexArrays(1, &mut vertex_array_id);
+  gl::BindVertexArray(vertex_array_id);
+
+  initialize_vbo(lines, &mut vertex_buffer_id, gl::ARRAY_BUFFER);
+
+  initialize_vertex_layout::<Vec3<GLfloat>>(program);
+
+  NormalLines {
+    vertex_array_id:  vertex_array_id,
+    vertex_buffer_id: vertex_buffer_id,
+    num_vertices:     num_vertices
+  }
+}
+
+unsafe fn initialize_sky(vs_src: &ShaderSource, fs_src: &ShaderSource) -> Sky {
+  let program = build_program(&[(gl::VERTEX_SHADER, vs_src), (gl::FRAGMENT_SHADER, fs_src)]);
+
+  // Core profiles need a vertex array bound to draw, even without attributes
+  let mut vertex_array_id = 0;
+  gl::GenVertexArrays(1, &mut vertex_array_id);
+
+  let uniforms = UniformTable::reflect(program, "sky");
+
+  Sky {
+    program:         program,
+    vertex_array_id: vertex_array_id,
+
+    inverse_view_model: uniforms.uniform("inverse_view_model"),
+    view_extent:        uniforms.uniform("view_extent"),
+    sky_color:          uniforms.uniform("sky_color"),
+    horizon_color:      uniforms.uniform("horizon_color"),
+    sun_size:           uniforms.uniform("sun_size"),
+    scattering_enabled: uniforms.uniform("scattering_enabled")
+  }
+}
+
+// Recreates the reflection and refraction targets to fit a new framebuffer size
+unsafe fn resize_water_targets(water: &mut Water, width: u32, height: u32) {
+  let target_width = std::cmp::max(width / WATER_TARGET_DIVISOR, 1);
+  let target_height = std::cmp::max(height / WATER_TARGET_DIVISOR, 1);
+
+  delete_water_target(&water.reflection);
+  delete_water_target(&water.refraction);
+  water.reflection = initialize_water_target(target_width, target_height);
+  water.refraction = initialize_water_target(target_width, target_height);
+}
+
+unsafe fn initialize_water_target(width: u32, height: u32) -> WaterTarget {
+  let mut framebuffer_id = 0;
+  let mut color_texture_id = 0;
+  let mut depth_buffer_id = 0;
+
+  gl::GenTextures(1, &mut color_texture_id);
+  gl::BindTexture(gl::TEXTURE_2D, color_texture_id);
+  gl::TexImage2D(gl::TEXTURE_2D, 0, gl::RGBA8 as GLint, width as GLint, height as GLint, 0, gl::RGBA, gl::UNSIGNED_BYTE, ptr::null());
+
+  gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+  gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
+  gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
+  gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
+
+  gl::GenFramebuffers(1, &mut framebuffer_id);
+  gl::BindFramebuffer(gl::FRAMEBUFFER, framebuffer_id);
+  gl::FramebufferTexture2D(gl::FRAMEBUFFER, gl::COLOR_ATTACHMENT0, gl::TEXTURE_2D, color_texture_id, 0);
+
+  gl::GenRenderbuffers(1, &mut depth_buffer_id);
+  gl::BindRenderbuffer(gl::RENDERBUFFER, depth_buffer_id);
+  gl::RenderbufferStorage(gl::RENDERBUFFER, gl::DEPTH_COMPONENT24, width as GLint, height as GLint);
+  gl::FramebufferRenderbuffer(gl::FRAMEBUFFER, gl::DEPTH_ATTACHMENT, gl::RENDERBUFFER, depth_buffer_id);
+
+  if gl::CheckFramebufferStatus(gl::FRAMEBUFFER) != gl::FRAMEBUFFER_COMPLETE {
+    fail!("Water framebuffer is incomplete");
+  }
+  gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+
+  WaterTarget {
+    framebuffer_id:   framebuffer_id,
+    color_texture_id: color_texture_id,
+    depth_buffer_id:  depth_buffer_id,
+    width:            width,
+    height:           height
+  }
+}
+
+unsafe fn delete_water_target(target: &WaterTarget) {
+  gl::DeleteFramebuffers(1, &target.framebuffer_id);
+  gl::DeleteTextures(1, &target.color_texture_id);
+  gl::DeleteRenderbuffers(1, &target.depth_buffer_id);
+}
+
+// Tileable ripple normals in RGB, from the analytic slope of a sum of RIPPLES
+fn compute_ripple_map(size: u32) -> ~[u8] {
+  let pi = std::f32::consts::PI;
+  let mut pixels: ~[u8] = ~[];
+
+  for v in range(0, size) {
+    for u in range(0, size) {
+      let (s, t) = (u as f32 / size as f32, v as f32 / size as f32);
+      let mut slope = Vec2::new(0.0f32, 0.0);
+
+      for &(fx, fy, phase) in RIPPLES.iter() {
+        // Smaller ripples are also lower, so every ripple is equally steep
+        let amplitude = 1.0 / (fx * fx + fy * fy).sqrt();
+        let c = (2.0 * pi * (fx * s + fy * t) + phase).cos() * amplitude * 2.0 * pi;
+        slope = slope.add_v(&Vec2::new(fx * c, fy * c));
+      }
+
+      let normal = Vec3::new(-slope.x, -slope.y, RIPPLES.len() as f32).normalize();
+      pixels.push(((normal.x * 0.5 + 0.5) * 255.0) as u8);
+      pixels.push(((normal.y * 0.5 + 0.5) * 255.0) as u8);
+      pixels.push(((normal.z * 0.5 + 0.5) * 255.0) as u8);
+    }
+  }
+
+  pixels
+}
+
+unsafe fn initialize_ripple_map(size: u32) -> GLuint {
+  let pixels = compute_ripple_map(size);
+  let mut texture_id = 0;
+
+  gl::GenTextures(1, &mut texture_id);
+  gl::BindTexture(gl::TEXTURE_2D, texture_id);
+  gl::PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+  gl::TexImage2D(gl::TEXTURE_2D, 0, gl::RGB8 as GLint, size as GLint, size as GLint, 0, gl::RGB, gl::UNSIGNED_BYTE, pixels.as_ptr() as GLeglImageOES);
+  gl::PixelStorei(gl::UNPACK_ALIGNMENT, 4);
+  gl::GenerateMipmap(gl::TEXTURE_2D);
+
+  gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+  gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR_MIPMAP_LINEAR as i32);
+  gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::REPEAT as i32);
+  gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::REPEAT as i32);
+
+  texture_id
+}
+
+unsafe fn initialize_text_renderer(vs_src: &ShaderSource, fs_src: &ShaderSource) -> TextRenderer {
+  let program = build_program(&[(gl::VERTEX_SHADER, vs_src), (gl::FRAGMENT_SHADER, fs_src)]);
+
+  let mut vertex_array_id = 0;
+  let mut vertex_buffer_id = 0;
+  let mut font_texture_id = 0;
+
+  gl::GenVertexArrays(1, &mut vertex_array_id);
+  gl::BindVertexArray(vertex_array_id);
+
+  gl::GenBuffers(1, &mut vertex_buffer_id);
+  gl::BindBuffer(gl::ARRAY_BUFFER, vertex_buffer_id);
+
+  initialize_vertex_layout::<TextVertex>(program);
+
+  // The atlas is 8-bit grayscale, so rows are not 4-byte aligned
+  let font = load_png_image(FONT_SRC);
+
+  gl::GenTextures(1, &mut font_texture_id);
+  gl::BindTexture(gl::TEXTURE_2D, font_texture_id);
+  gl::PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+  gl::TexImage2D(gl::TEXTURE_2D, 0, gl::R8 as GLint, font.width as GLint, font.height as GLint, 0, gl::RED, gl::UNSIGNED_BYTE, font.pixels.as_ptr() as GLeglImageOES);
+  gl::PixelStorei(gl::UNPACK_ALIGNMENT, 4);
+
+  gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::NEAREST as i32);
+  gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::NEAREST as i32);
+
+  let uniforms = UniformTable::reflect(program, "text");
+
+  TextRenderer {
+    program:          program,
+    vertex_array_id:  vertex_array_id,
+    vertex_buffer_id: vertex_buffer_id,
+    font_texture_id:  font_texture_id,
+
+    screen_size: uniforms.uniform("screen_size"),
+    color:       uniforms.uniform("color"),
+    font:        uniforms.uniform("font")
+  }
+}
+
+// Creates and binds a framebuffer with color and depth renderbuffers
+unsafe fn initialize_offscreen_target(width: u32, height: u32) -> (GLuint, GLuint, GLuint) {
+  let mut framebuffer_id = 0;
+  let mut color_buffer_id = 0;
+  let mut depth_buffer_id = 0;
+
+  gl::GenFramebuffers(1, &mut framebuffer_id);
+  gl::BindFramebuffer(gl::FRAMEBUFFER, framebuffer_id);
+
+  gl::GenRenderbuffers(1, &mut color_buffer_id);
+  gl::BindRenderbuffer(gl::RENDERBUFFER, color_buffer_id);
+  gl::RenderbufferStorage(gl::RENDERBUFFER, gl::RGBA8, width as GLint, height as GLint);
+  gl::FramebufferRenderbuffer(gl::FRAMEBUFFER, gl::COLOR_ATTACHMENT0, gl::RENDERBUFFER, color_buffer_id);
+
+  gl::GenRenderbuffers(1, &mut depth_buffer_id);
+  gl::BindRenderbuffer(gl::RENDERBUFFER, depth_buffer_id);
+  gl::RenderbufferStorage(gl::RENDERBUFFER, gl::DEPTH_COMPONENT24, width as GLint, height as GLint);
+  gl::FramebufferRenderbuffer(gl::FRAMEBUFFER, gl::DEPTH_ATTACHMENT, gl::RENDERBUFFER, depth_buffer_id);
+
+  if gl::CheckFramebufferStatus(gl::FRAMEBUFFER) != gl::FRAMEBUFFER_COMPLETE {
+    fail!("Offscreen framebuffer is incomplete");
+  }
+
+  gl::Viewport(0, 0, width as GLint, height as GLint);
+
+  (framebuffer_id, color_buffer_id, depth_buffer_id)
+}
+
+// Number of GLfloats in an attribute of the given GLSL type, or 0 for other types
+fn attribute_type_components(kind: GLenum) -> GLint {
+  match kind {
+    gl::FLOAT      => 1,
+    gl::FLOAT_VEC2 => 2,
+    gl::FLOAT_VEC3 => 3,
+    gl::FLOAT_VEC4 => 4,
+    _ => 0
+  }
+}
+
+// Points the attributes of the bound vertex array into the bound vertex
+// buffer, laid out as T describes. Attributes are looked up by name in the
+// program, and the program's active attributes are checked against the layout,
+// so a missing attribute or a wrong component count fails here rather than
+// rendering garbage.
+unsafe fn initialize_vertex_layout<T: VertexFormat>(program: GLuint) {
+  let attributes = VertexFormat::attributes(None::<T>);
+  let stride = mem::size_of::<T>() as GLint;
+
+  let mut num_active = 0;
+  gl::GetProgramiv(program, gl::ACTIVE_ATTRIBUTES, &mut num_active);
+
+  for i in range(0, num_active as GLuint) {
+    let mut name_buffer = vec::from_elem(256, 0u8);
+    let mut length = 0;
+    let mut size = 0;
+    let mut kind = 0;
+    gl::GetActiveAttrib(program, i, name_buffer.len() as GLint, &mut length, &mut size, &mut kind,
+                        name_buffer.as_mut_ptr() as *mut GLchar);
+    let name = str::from_utf8(name_buffer.slice(0, length as uint)).unwrap_or("?");
+
+    // Built in inputs like gl_VertexID need no buffer
+    if name.starts_with("gl_") { continue }
+
+    match attributes.iter().find(|a| a.name == name) {
+      Some(attribute) => if attribute_type_components(kind) != attribute.components {
+        fail!("Vertex attribute {} has {} components, but the shader reads {}",
+              name, attribute.components, attribute_type_components(kind));
+      },
+      None => fail!("Shader reads vertex attribute {}, which the vertex format does not provide", name)
+    }
+  }
+
+  for attribute in attributes.iter() {
+    let location = attribute.name.with_c_str(|ptr| gl::GetAttribLocation(program, ptr));
+
+    // Unused by this program, e.g. normals in a depth only pass
+    if location < 0 { continue }
+
+    gl::EnableVertexAttribArray(location as GLuint);
+    gl::VertexAttribPointer(location as GLuint, attribute.components, gl::FLOAT, gl::FALSE, stride,
+                            cast::transmute(attribute.offset));
+  }
+}
+
+unsafe fn initialize_vbo<T>(vec: ~[T], buf_id: &mut GLuint, array_type: GLenum) {
+  let vec_bytes = (vec.len() * mem::size_of::<T>()) as GLsizeiptr;
+  let vec_ptr = cast::transmute(&vec[0]);
+
+  gl::GenBuffers(1, buf_id);
+  gl::BindBuffer(array_type, *buf_id);
+  gl::BufferData(array_type, vec_bytes, vec_ptr, gl::STATIC_DRAW);
+}
+
+unsafe fn initialize_world() {
+
+  let xr = deg(15.0f32).to_rad().s;
+  let yr = deg(0.0f32).to_rad().s;;
+  let zr = deg(-15.0f32).to_rad().s;
+
+  world.rotation = Quat::new(1.0f32, xr, yr, zr); // Rotate the world
+  world.translation = Vec3::new(-1.0f32, -1.0f32, 0.0f32); // Center the world
+  world.scale = 0.001f32; // Zoom far out
+
+  world.model_matrix = Transform3D::new(world.scale, world.rotation, world.translation).to_mat4();
+}
+
+unsafe fn initialize_camera() {
+
+  camera.rotation = Quat::identity(); // No rotation
+  camera.translation = Vec3::zero(); // No translation
+  camera.scale = 1f32; // No zooming
+
+  // camera.view_matrix = Transform3D::new(camera.scale, camera.rotation, camera.translation).to_mat4();
+  camera.view_matrix = Mat4::identity();
+}
+
+unsafe fn initialize_screen() {
+  screen.projection_matrix = Mat4::identity();
+}
+
+unsafe fn update_camera_block(scene: &Scene, view_matrix: &Mat4<f32>) {
+  update_uniform_buffer(scene.camera_buffer_id, &CameraBlock {
+    projection: screen.projection_matrix,
+    view:       *view_matrix
+  });
+}
+
+unsafe fn update_light_block(scene: &Scene) {
+  update_uniform_buffer(scene.light_buffer_id, &LightBlock {
+    sun_color:     world.sunlight.color,
+    sun_intensity: world.sunlight.intensity,
+    sun_direction: world.sunlight.direction,
+    padding:       0.0,
+    ambient:       world.ambient,
+    padding2:      0.0
+  });
+}
+
+unsafe fn initialize_shader_data(shader_program: GLuint) {
+  let uniforms = UniformTable::reflect(shader_program, "terrain");
+
+  vs_data.model_matrix       = uniforms.uniform("M");
+  vs_data.clip_plane         = uniforms.uniform("clip_plane");
+  if flat_shading_available {
+    vs_data.flat_shading     = uniforms.uniform("flat_shading");
+  }
+
+  fs_data.shadows_enabled    = uniforms.uniform("shadows_enabled");
+  if SHADOW_MAPS {
+    fs_data.shadow_map       = uniforms.uniform("shadow_map");
+    fs_data.light_matrices   = uniforms.uniform("light_matrices");
+    fs_data.cascade_splits   = uniforms.uniform("cascade_splits");
+  }
+  fs_data.lightmap           = uniforms.uniform("lightmap");
+  fs_data.lightmap_enabled   = uniforms.uniform("lightmap_enabled");
+  fs_data.land_use           = uniforms.uniform("land_use");
+  fs_data.land_use_enabled   = uniforms.uniform("land_use_enabled");
+  fs_data.land_use_opacity   = uniforms.uniform("land_use_opacity");
+  fs_data.rivers             = uniforms.uniform("rivers");
+  fs_data.rivers_enabled     = uniforms.uniform("rivers_enabled");
+  fs_data.regions            = uniforms.uniform("regions");
+  fs_data.regions_enabled    = uniforms.uniform("regions_enabled");
+  fs_data.viewshed           = uniforms.uniform("viewshed");
+  fs_data.viewshed_enabled   = uniforms.uniform("viewshed_enabled");
+  fs_data.fog                = fog_uniforms(&uniforms);
+  fs_data.chunk_lines_enabled = uniforms.uniform("chunk_lines_enabled");
+  fs_data.chunk_size         = uniforms.uniform("chunk_size");
+  fs_data.chunk_line_color   = uniforms.uniform("chunk_line_color");
+  fs_data.contours_enabled   = uniforms.uniform("contours_enabled");
+  fs_data.contour_interval   = uniforms.uniform("contour_interval");
+  fs_data.contour_index_every = uniforms.uniform("contour_index_every");
+  fs_data.contour_color      = uniforms.uniform("contour_color");
+  fs_data.debug_view         = uniforms.uniform("debug_view");
+  fs_data.height_range       = uniforms.uniform("height_range");
+  fs_data.lod_level          = uniforms.uniform("lod_level");
+  fs_data.materials          = uniforms.uniform("materials");
+  fs_data.triplanar_enabled  = uniforms.uniform("triplanar_enabled");
+
+  "out_color".with_c_str(|ptr| gl::BindFragDataLocation(shader_program, 0, ptr));
+}
+
+// The material table never changes, so it is uploaded once
+unsafe fn initialize_materials(shader_program: GLuint) {
+  assert!(MATERIALS.len() <= MAX_MATERIALS);
+
+  let uniforms = UniformTable::reflect(shader_program, "terrain");
+
+  let textures: ~[i32] = MATERIALS.iter().map(|m| m.texture as i32).collect();
+  let colors: ~[Vec3<f32>] = MATERIALS.iter().map(|m| m.color).collect();
+  let blends: ~[Vec2<f32>] = MATERIALS.iter().map(|m| Vec2::new(m.blend_start, m.blend_end)).collect();
+  let scales: ~[f32] = MATERIALS.iter().map(|m| m.scale).collect();
+
+  uniforms.uniform::<i32>("num_materials").set(MATERIALS.len() as i32);
+  uniforms.uniform::<i32>("material_textures").set_array(textures);
+  uniforms.uniform::<Vec3<f32>>("material_colors").set_array(colors);
+  uniforms.uniform::<Vec2<f32>>("material_blends").set_array(blends);
+  uniforms.uniform::<f32>("material_scales").set_array(scales);
+
+  uniforms.uniform::<i32>("cliff_material").set(CLIFF_MATERIAL as i32);
+  uniforms.uniform::<Vec2<f32>>("cliff_slopes").set(Vec2::new(CLIFF_SLOPE_START, CLIFF_SLOPE_END));
+}
+
+// Expands a loaded PNG to RGBA, whatever its color type
+fn rgba_pixels(file_path: &str, image: &png::Image) -> ~[u8] {
+  let channels = match image.color_type {
+    png::K8    => 1,
+    png::KA8   => 2,
+    png::RGB8  => 3,
+    png::RGBA8 => 4,
+    _ => fail!("{}: unsupported PNG color type", file_path)
+  };
+
+  let mut pixels: ~[u8] = ~[];
+  for texel in image.pixels.chunks(channels) {
+    match channels {
+      1 => pixels.push_all([texel[0], texel[0], texel[0], 255]),
+      2 => pixels.push_all([texel[0], texel[0], texel[0], texel[1]]),
+      3 => pixels.push_all([texel[0], texel[1], texel[2], 255]),
+      _ => pixels.push_all(texel)
+    }
+  }
+  pixels
+}
+
+// Loads MATERIAL_TEXTURES into the layers of a mipmapped texture array
+unsafe fn initialize_material_textures() -> GLuint {
+  let images: ~[png::Image] = MATERIAL_TEXTURES.iter().map(|&path| load_png_image(path)).collect();
+  let (width, height) = (images[0].width, images[0].height);
+  let mut texture_id = 0;
+
+  gl::GenTextures(1, &mut texture_id);
+  gl::BindTexture(gl::TEXTURE_2D_ARRAY, texture_id);
+  gl::TexImage3D(gl::TEXTURE_2D_ARRAY, 0, gl::RGBA8 as GLint, width as GLint, height as GLint,
+                 images.len() as GLint, 0, gl::RGBA, gl::UNSIGNED_BYTE, ptr::null());
+
+  for (layer, image) in images.iter().enumerate() {
+    let path = MATERIAL_TEXTURES[layer];
+    if image.width != width || image.height != height {
+      fail!("{}: material textures must all be {}x{}", path, width, height);
+    }
+
+    let pixels = rgba_pixels(path, image);
+    gl::TexSubImage3D(gl::TEXTURE_2D_ARRAY, 0, 0, 0, layer as GLint, width as GLint, height as GLint, 1,
+                      gl::RGBA, gl::UNSIGNED_BYTE, pixels.as_ptr() as GLeglImageOES);
+  }
+
+  gl::GenerateMipmap(gl::TEXTURE_2D_ARRAY);
+  gl::TexParameteri(gl::TEXTURE_2D_ARRAY, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+  gl::TexParameteri(gl::TEXTURE_2D_ARRAY, gl::TEXTURE_MIN_FILTER, gl::LINEAR_MIPMAP_LINEAR as i32);
+  gl::TexParameteri(gl::TEXTURE_2D_ARRAY, gl::TEXTURE_WRAP_S, gl::REPEAT as i32);
+  gl::TexParameteri(gl::TEXTURE_2D_ARRAY, gl::TEXTURE_WRAP_T, gl::REPEAT as i32);
+
+  texture_id
+}
+
+// Land use  -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- --
+
+// Class of a control map pixel as an index into LAND_USE_CLASSES plus one, or
+// 0 if unassigned
+fn land_use_class(rgba: &[u8], grayscale: bool) -> u8 {
+  if rgba[3] < 128 || (grayscale && rgba[0] <= LAND_USE_NO_DATA) {
+    return 0
+  }
+
+  let mut best = 0;
+  let mut best_distance = std::int::MAX;
+
+  for (i, class) in LAND_USE_CLASSES.iter().enumerate() {
+    if grayscale {
+      if rgba[0] <= class.max_value { return (i + 1) as u8 }
+    } else {
+      let distance = range(0u, 3).fold(0, |d, c| {
+        let delta = rgba[c] as int - class.color[c] as int;
+        d + delta * delta
+      });
+      if distance < best_distance {
+        best = i + 1;
+        best_distance = distance;
+      }
+    }
+  }
+  best as u8
+}
+
+// Loads a land use control map and resamples it to the height grid, returning
+// the class of every cell in grid order. The map covers the whole terrain
+// whatever its size, with rows running along grid x like the heightmap.
+fn load_land_use(file_path: &str, grid: &HeightGrid) -> ~[u8] {
+  let image = load_png_image(file_path);
+  let pixels = rgba_pixels(file_path, &image);
+  let grayscale = match image.color_type {
+    png::K8 | png::KA8 => true,
+    _ => false
+  };
+
+  let mut classes: ~[u8] = ~[];
+
+  // Classes can't be interpolated, so take the nearest pixel
+  for x in range(0, grid.width) {
+    for y in range(0, grid.height) {
+      let row = std::cmp::min(x * image.height / grid.width, image.height - 1);
+      let column = std::cmp::min(y * image.width / grid.height, image.width - 1);
+      let i = ((row * image.width + column) * 4) as uint;
+      classes.push(land_use_class(pixels.slice(i, i + 4), grayscale));
+    }
+  }
+  classes
+}
+
+fn land_use_counts(classes: &[u8]) -> ~[uint] {
+  if classes.len() == 0 { return ~[] }
+
+  let mut counts = vec::from_elem(LAND_USE_CLASSES.len(), 0u);
+  for &class in classes.iter() {
+    if class > 0 { counts[(class - 1) as uint] += 1 }
+  }
+  counts
+}
+
+// Class colors per cell, transparent where unassigned. Laid out like the lightmap.
+unsafe fn initialize_land_use_texture(classes: &[u8], grid: &HeightGrid) -> GLuint {
+  let mut pixels: ~[u8] = ~[];
+  for &class in classes.iter() {
+    if class == 0 {
+      pixels.push_all([0, 0, 0, 0]);
+    } else {
+      let color = LAND_USE_CLASSES[(class - 1) as uint].color;
+      pixels.push_all([color[0], color[1], color[2], 255]);
+    }
+  }
+
+  let mut texture_id = 0;
+
+  gl::GenTextures(1, &mut texture_id);
+  gl::BindTexture(gl::TEXTURE_2D, texture_id);
+  gl::TexImage2D(gl::TEXTURE_2D, 0, gl::RGBA8 as GLint, grid.height as GLint, grid.width as GLint, 0, gl::RGBA, gl::UNSIGNED_BYTE, pixels.as_ptr() as GLeglImageOES);
+
+  gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::NEAREST as i32);
+  gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::NEAREST as i32);
+  gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
+  gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
+
+  texture_id
+}
+
+// Camera flythrough -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- --
+
+// Loads keyframes from a text file with one keyframe per line:
+//
+//   time  tx ty tz  qs qx qy qz  scale
+//
+// where t is the camera translation and q its rotation quaternion, i.e. the
+// same values the camera controls modify. Rotations are normalized, since slerp
+// needs unit quaternions. Blank lines and lines starting with '#' are ignored.
+// Press P in the viewer to print the current pose as a line.
+fn load_keyframes(file_path: &str) -> ~[Keyframe] {
+  let src = load_text_file(file_path);
+  let mut keyframes: ~[Keyframe] = ~[];
+
+  for (n, line) in src.lines().enumerate() {
+    let line = line.trim();
+    if line.len() == 0 || line.starts_with("#") { continue }
+
+    let fields: ~[f32] = line.words().map(|word| {
+      match from_str::<f32>(word) {
+        Some(value) => value,
+        None => fail!("{}:{}: invalid number '{}'", file_path, n + 1, word)
+      }
+    }).collect();
+
+    if fields.len() != 9 {
+      fail!("{}:{}: expected 9 values, found {}", file_path, n + 1, fields.len());
+    }
+
+    if keyframes.len() > 0 && fields[0] <= keyframes[keyframes.len() - 1].time {
+      fail!("{}:{}: keyframe times must be increasing", file_path, n + 1);
+    }
+
+    let length = fields.slice(4, 8).iter().fold(0.0f32, |sum, &q| sum + q * q).sqrt();
+    if length == 0.0 {
+      fail!("{}:{}: the rotation quaternion is zero", file_path, n + 1);
+    }
+
+    keyframes.push(Keyframe {
+      time:        fields[0],
+      translation: Vec3::new(fields[1], fields[2], fields[3]),
+      rotation:    Quat::new(fields[4] / length, fields[5] / length, fields[6] / length, fields[7] / length),
+      scale:       fields[8]
+    });
+  }
+
+  if keyframes.len() < 2 {
+    fail!("{}: a flythrough needs at least two keyframes", file_path);
+  }
+  keyframes
+}
+
+fn catmull_rom(p0: Vec3<f32>, p1: Vec3<f32>, p2: Vec3<f32>, p3: Vec3<f32>, t: f32) -> Vec3<f32> {
+  let t2 = t * t;
+  let t3 = t2 * t;
+
+  let a = p1.mul_s(2.0);
+  let b = (p2 - p0).mul_s(t);
+  let c = (p0.mul_s(2.0) - p1.mul_s(5.0) + p2.mul_s(4.0) - p3).mul_s(t2);
+  let d = (p1.mul_s(3.0) - p0 - p2.mul_s(3.0) + p3).mul_s(t3);
+
+  (a + b + c + d).mul_s(0.5)
+}
+
+// Interpolates the camera pose at the given time. Translation follows a
+// Catmull-Rom spline through the keyframes, rotation is slerped and scale is
+// interpolated linearly. Times outside the path clamp to the end keyframes.
+fn sample_flythrough(keyframes: &[Keyframe], time: f32) -> Keyframe {
+  let last = keyframes.len() - 1;
+
+  if time <= keyframes[0].time { return keyframes[0] }
+  if time >= keyframes[last].time { return keyframes[last] }
+
+  let mut i = 0;
+  while keyframes[i + 1].time < time { i += 1 }
+
+  // Duplicate the end points so the spline passes through the first and last keyframes
+  let k0 = keyframes[if i > 0 {i - 1} else {i}];
+  let k1 = keyframes[i];
+  let k2 = keyframes[i + 1];
+  let k3 = keyframes[if i + 2 <= last {i + 2} else {last}];
+
+  let t = (time - k1.time) / (k2.time - k1.time);
+
+  Keyframe {
+    time:        time,
+    translation: catmull_rom(k0.translation, k1.translation, k2.translation, k3.translation, t),
+    rotation:    k1.rotation.slerp(&k2.rotation, t),
+    scale:       k1.scale + (k2.scale - k1.scale) * t
+  }
+}
+
+unsafe fn apply_keyframe(keyframe: &Keyframe) {
+  camera.translation = keyframe.translation;
+  camera.rotation = keyframe.rotation;
+  camera.scale = keyframe.scale;
+
+  update_view_matrix();
+}
+
+unsafe fn print_camera_keyframe(time: f64) {
+  println!("{} {} {} {} {} {} {} {} {}", time,
+    camera.translation.x, camera.translation.y, camera.translation.z,
+    camera.rotation.s, camera.rotation.v.x, camera.rotation.v.y, camera.rotation.v.z,
+    camera.scale);
+}
+
+// Contours  -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- --
+
+// Traces the grid at every multiple of interval with marching squares. The
+// shader draws the same contours per pixel; these are for labels and export.
+fn extract_contours(grid: &HeightGrid, interval: f32) -> ~[Contour] {
+  let min = grid.heights.iter().fold(std::f32::INFINITY, |a, &b| a.min(b));
+  let max = grid.heights.iter().fold(-std::f32::INFINITY, |a, &b| a.max(b));
+  let mut contours = ~[];
+
+  for i in range((min / interval).ceil() as int, (max / interval).floor() as int + 1) {
+    let elevation = i as f32 * interval;
+    for points in trace_contour_level(grid, elevation).move_iter() {
+      contours.push(Contour {
+        elevation: elevation,
+        index:     i % CONTOUR_INDEX_EVERY as int == 0,
+        points:    points
+      });
+    }
+  }
+  contours
+}
+
+// Polylines where the grid crosses one elevation. Every crossing lies on a grid
+// edge: edges along x are numbered x * height + y, those along y follow them.
+// Saddle cells are split the way the average of their corners falls.
+fn trace_contour_level(grid: &HeightGrid, level: f32) -> ~[~[Vec2<f32>]] {
+  let (width, height) = (grid.width, grid.height);
+  let num_x_edges = (width * height) as uint;
+
+  let edge_x = |x: u32, y: u32| (x * height + y) as uint;
+  let edge_y = |x: u32, y: u32| num_x_edges + (x * height + y) as uint;
+
+  let crossing = |edge: uint| -> Vec2<f32> {
+    let (x0, y0, x1, y1) = if edge < num_x_edges {
+      let (x, y) = ((edge as u32) / height, (edge as u32) % height);
+      (x, y, x + 1, y)
+    } else {
+      let (x, y) = (((edge - num_x_edges) as u32) / height, ((edge - num_x_edges) as u32) % height);
+      (x, y, x, y + 1)
+    };
+    let (a, b) = (grid.get(x0, y0), grid.get(x1, y1));
+    let t = (level - a) / (b - a);
+    Vec2::new(x0 as f32 + t * (x1 - x0) as f32, y0 as f32 + t * (y1 - y0) as f32)
+  };
+
+  // Each segment joins the crossings on two edges of a cell
+  let mut segments: ~[(uint, uint)] = ~[];
+
+  for x in range(0, width - 1) {
+    for y in range(0, height - 1) {
+      // Corners in order around the cell, edge i joining corner i to the next
+      let corners = [grid.get(x, y), grid.get(x + 1, y), grid.get(x + 1, y + 1), grid.get(x, y + 1)];
+      let edges = [edge_x(x, y), edge_y(x + 1, y), edge_x(x, y + 1), edge_y(x, y)];
+
+      // Bit i is set if corner i is at or above the level
+      let case = range(0u, 4).fold(0u, |case, i| if corners[i] >= level { case | 1 << i } else { case });
+      let above = |i: uint| case >> (i % 4) & 1;
+
+      match case {
+        0 | 15 => {}
+        // Saddles, with opposite corners on the same side
+        5 | 10 => {
+          let center = (corners[0] + corners[1] + corners[2] + corners[3]) / 4.0;
+          if (corners[0] >= level) == (center >= level) {
+            segments.push((edges[0], edges[1]));
+            segments.push((edges[2], edges[3]));
+          } else {
+            segments.push((edges[3], edges[0]));
+            segments.push((edges[1], edges[2]));
+          }
+        }
+        _ => {
+          let mut crossed = [0u, 0];
+          let mut n = 0;
+          for i in range(0u, 4) {
+            if above(i) != above(i + 1) {
+              crossed[n] = i;
+              n += 1;
+            }
+          }
+          segments.push((edges[crossed[0]], edges[crossed[1]]));
+        }
+      }
+    }
+  }
+
+  if segments.len() == 0 { return ~[] }
+
+  // The (at most two) segments meeting at each edge
+  let mut links: ~[Option<uint>] = vec::from_elem(num_x_edges * 4, None);
+  for (i, &(a, b)) in segments.iter().enumerate() {
+    for &edge in [a, b].iter() {
+      let slot = if links[edge * 2].is_none() { edge * 2 } else { edge * 2 + 1 };
+      links[slot] = Some(i);
+    }
+  }
+
+  // Chain the segments, following each line both ways from where it was found.
+  // Closed lines end on the edge they started from.
+  let mut used = vec::from_elem(segments.len(), false);
+  let mut lines = ~[];
+
+  for start in range(0, segments.len()) {
+    if used[start] { continue }
+    used[start] = true;
+
+    let (first, last) = segments[start];
+    let forward = follow_contour(segments, links, used.as_mut_slice(), last, start);
+    let backward = follow_contour(segments, links, used.as_mut_slice(), first, start);
+
+    let mut edges: ~[uint] = backward.move_rev_iter().collect();
+    edges.push(first);
+    edges.push(last);
+    edges.push_all(forward);
+
+    let mut points = ~[];
+    for &edge in edges.iter() {
+      points.push(crossing(edge));
+    }
+    lines.push(points);
+  }
+  lines
+}
+
+// Walks from a segment across the given edge to the next unused segment and
+// on, returning the far edges of the segments passed
+fn follow_contour(segments: &[(uint, uint)], links: &[Option<uint>], used: &mut [bool],
+                  mut edge: uint, mut segment: uint) -> ~[uint] {
+  let mut edges = ~[];
+  loop {
+    let next = match (links[edge * 2], links[edge * 2 + 1]) {
+      (Some(a), Some(b)) => if a == segment { b } else { a },
+      _ => break
+    };
+    if used[next] { break }
+    used[next] = true;
+
+    let (a, b) = segments[next];
+    edge = if a == edge { b } else { a };
+    segment = next;
+    edges.push(edge);
+  }
+  edges
+}
+
+// Anchors the labels of the longer index contours halfway along them
+fn contour_labels(contours: &[Contour]) -> ~[ContourLabel] {
+  contours.iter().filter(|c| c.index && c.points.len() >= CONTOUR_LABEL_MIN_POINTS).map(|c| {
+    let p = c.points[c.points.len() / 2];
+    ContourLabel { position: Vec3::new(p.x, p.y, -c.elevation), text: format!("{}", c.elevation) }
+  }).collect()
+}
+
+fn write_text_file(file_path: &str, text: &str) {
+  match File::create(&Path::new(file_path)).write_str(text) {
+    Ok(_) => {},
+    Err(s) => fail!("{}: {}", file_path, s)
+  }
+}
+
+// A GeoJSON FeatureCollection of LineStrings, each given as the members of its
+// properties object and its grid points. The heightmap is not georeferenced,
+// so coordinates are its pixels: column, then row.
+fn write_line_features(features: &[(~str, &[Vec2<f32>])], file_path: &str) {
+  let mut json = ~"{\"type\":\"FeatureCollection\",\"features\":[\n";
+
+  for (i, &(ref properties, points)) in features.iter().enumerate() {
+    let coordinates: ~[~str] = points.iter().map(|p| format!("[{:.3f},{:.3f}]", p.y, p.x)).collect();
+
+    if i > 0 { json.push_str(",\n") }
+    json.push_str("{\"type\":\"Feature\",\"properties\":{");
+    json.push_str(properties.as_slice());
+    json.push_str("},\"geometry\":{\"type\":\"LineString\",\"coordinates\":[");
+    json.push_str(coordinates.connect(","));
+    json.push_str("]}}");
+  }
+  json.push_str("\n]}\n");
+
+  write_text_file(file_path, json);
+}
+
+fn write_contours_geojson(contours: &[Contour], file_path: &str) {
+  let features: ~[(~str, &[Vec2<f32>])] = contours.iter().map(|contour|
+    (format!("\"elevation\":{},\"index\":{}", contour.elevation, contour.index), contour.points.as_slice())).collect();
+  write_line_features(features, file_path);
+}
+
+// The contours as an SVG with one unit per heightmap pixel, index contours
+// thicker and labelled like in the viewer
+fn write_contours_svg(contours: &[Contour], width: u32, height: u32, file_path: &str) {
+  let color = format!("rgb({},{},{})", (CONTOUR_COLOR.x * 255.0) as u8, (CONTOUR_COLOR.y * 255.0) as u8,
+                      (CONTOUR_COLOR.z * 255.0) as u8);
+  let mut svg = format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\">\n\
+                         <g fill=\"none\" stroke=\"{}\" stroke-linejoin=\"round\">\n",
+                        width, height, width, height, color);
+
+  for contour in contours.iter() {
+    let points: ~[~str] = contour.points.iter().map(|p| format!("{:.2f},{:.2f}", p.y, p.x)).collect();
+    svg.push_str(format!("<polyline stroke-width=\"{}\" points=\"{}\"/>\n",
+                         if contour.index { 1.0 } else { 0.4 }, points.connect(" ")));
+  }
+  svg.push_str(format!("</g>\n<g fill=\"{}\" font-family=\"sans-serif\" font-size=\"6\" text-anchor=\"middle\">\n", color));
+
+  for label in contour_labels(contours).iter() {
+    svg.push_str(format!("<text x=\"{:.2f}\" y=\"{:.2f}\">{}</text>\n", label.position.y, label.position.x, label.text));
+  }
+  svg.push_str("</g>\n</svg>\n");
+
+  write_text_file(file_path, svg);
+}
+
+// Terrain rasters  -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- --
+
+// Writes hillshade, slope, aspect and curvature of the grid as grayscale PNGs
+// of the given bit depth, laid out like the lightmap:
+//   hillshade.png  lit by the lightmap's sun, black in shadowed directions
+//   slope.png      0 to 90 degrees
+//   aspect.png     downslope direction, 0 to 360 degrees clockwise from north
+//                  mapped from 1 up, with 0 where the terrain is flat
+//   curvature.png  -CURVATURE_RANGE to CURVATURE_RANGE, mid gray where flat
+fn export_rasters(grid: &HeightGrid, output_dir: &str, bit_depth: uint) {
+  for &(name, ref values) in terrain_rasters(grid, bit_depth).iter() {
+    let file_path = format!("{}/{}.png", output_dir, name);
+    if DEBUG { println!("Writing {}", file_path) }
+    write_raster(grid, values.as_slice(), bit_depth, file_path);
+  }
+}
+
+// The values of the rasters export_rasters writes, from 0 to 1, by name
+fn terrain_rasters(grid: &HeightGrid, bit_depth: uint) -> ~[(&'static str, ~[f32])] {
+  let (sun_dx, sun_dy) = azimuth_step(LIGHTMAP_SUN_AZIMUTH);
+  let altitude = LIGHTMAP_SUN_ALTITUDE.to_radians();
+  let sun = Vec3::new(sun_dx * altitude.cos(), sun_dy * altitude.cos(), altitude.sin());
+  let max = raster_max(bit_depth);
+
+  let mut hillshade = ~[];
+  let mut slope = ~[];
+  let mut aspect = ~[];
+  let mut curvature = ~[];
+
+  for x in range(0, grid.width) {
+    for y in range(0, grid.height) {
+      let (dzdx, dzdy) = grid.gradient(x, y);
+
+      hillshade.push(Vec3::new(-dzdx, -dzdy, 1.0).normalize().dot(&sun).max(0.0));
+      slope.push(grid.slope(x, y) / 90.0);
+
+      // The downslope step (-dzdx, -dzdy) as an azimuth, see azimuth_step
+      aspect.push(if dzdx.abs() < FLAT_GRADIENT && dzdy.abs() < FLAT_GRADIENT {
+        0.0
+      } else {
+        let degrees = (-dzdy).atan2(&dzdx).to_degrees();
+        let degrees = if degrees < 0.0 { degrees + 360.0 } else { degrees };
+        (1.0 + degrees / 360.0 * (max - 1.0)) / max
+      });
+
+      curvature.push((grid.curvature(x, y) / CURVATURE_RANGE * 0.5 + 0.5).max(0.0).min(1.0));
+    }
+  }
+
+  ~[("hillshade", hillshade), ("slope", slope), ("aspect", aspect), ("curvature", curvature)]
+}
+
+fn raster_max(bit_depth: uint) -> f32 {
+  if bit_depth == 16 { 65535.0 } else { 255.0 }
+}
+
+// Stores values from 0 to 1 as a grayscale PNG
+fn write_raster(grid: &HeightGrid, values: &[f32], bit_depth: uint, file_path: &str) {
+  let max = raster_max(bit_depth);
+  let samples: ~[u16] = values.iter().map(|&value| (value * max).round() as u16).collect();
+  write_gray_png(grid, samples, bit_depth, file_path);
+}
+
+// Stores samples as a grayscale PNG laid out like the lightmap, 16 bit samples big endian
+fn write_gray_png(grid: &HeightGrid, samples: &[u16], bit_depth: uint, file_path: &str) {
+  let mut pixels: ~[u8] = ~[];
+
+  for &sample in samples.iter() {
+    if bit_depth == 16 {
+      pixels.push((sample >> 8) as u8);
+    }
+    pixels.push(sample as u8);
+  }
+
+  let color_type = if bit_depth == 16 { png::K16 } else { png::K8 };
+  let image = png::Image { width: grid.height, height: grid.width, color_type: color_type, pixels: pixels };
+  match png::store_png(&image, &Path::new(file_path)) {
+    Ok(()) => {},
+    Err(s) => fail!(s)
+  }
+}
+
+// Hydrology  -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- --
+
+// A cell waiting in the priority flood. The queue pops its greatest element,
+// so lower cells compare greater.
+struct FloodCell {
+  height: f32,
+  index:  uint
+}
+
+impl Eq for FloodCell {
+  fn eq(&self, other: &FloodCell) -> bool {
+    self.height == other.height && self.index == other.index
+  }
+}
+
+impl Ord for FloodCell {
+  fn lt(&self, other: &FloodCell) -> bool {
+    self.height > other.height || (self.height == other.height && self.index > other.index)
+  }
+}
+
+// Cell index of the neighbour in direction D8_OFFSETS[k], if it is on the grid
+fn d8_neighbour(grid: &HeightGrid, index: uint, k: uint) -> Option<uint> {
+  let (dx, dy) = D8_OFFSETS[k];
+  let x = (index / grid.width as uint) as int + dx;
+  let y = (index % grid.width as uint) as int + dy;
+
+  if x < 0 || y < 0 || x >= grid.width as int || y >= grid.height as int {
+    None
+  } else {
+    Some((x * grid.width as int + y) as uint)
+  }
+}
+
+fn d8_distance(k: uint) -> f32 {
+  if k % 2 == 1 { std::f32::consts::SQRT2 } else { 1.0 }
+}
+
+// Fills depressions with the priority flood (Barnes et al. 2014): cells are
+// visited from the edges inwards, lowest first, and any cell lower than the one
+// it is reached from is raised just above it. Returns the filled heights and
+// the cells in the order visited, which is by increasing filled height.
+fn fill_depressions(grid: &HeightGrid) -> (~[f32], ~[uint]) {
+  let mut filled = grid.heights.clone();
+  let mut visited = vec::from_elem(filled.len(), false);
+  let mut order = vec::with_capacity(filled.len());
+  let mut queue = PriorityQueue::new();
+
+  for x in range(0, grid.width) {
+    for y in range(0, grid.height) {
+      if x == 0 || y == 0 || x == grid.width - 1 || y == grid.height - 1 {
+        let index = (x * grid.width + y) as uint;
+        visited[index] = true;
+        queue.push(FloodCell { height: filled[index], index: index });
+      }
+    }
+  }
+
+  while !queue.is_empty() {
+    let cell = queue.pop();
+    order.push(cell.index);
+
+    for k in range(0u, 8) {
+      match d8_neighbour(grid, cell.index, k) {
+        Some(n) if !visited[n] => {
+          visited[n] = true;
+          filled[n] = filled[n].max(cell.height + FILL_EPSILON);
+          queue.push(FloodCell { height: filled[n], index: n });
+        }
+        _ => {}
+      }
+    }
+  }
+  (filled, order)
+}
+
+// The neighbour with the steepest drop, if any is lower
+fn d8_downstream(grid: &HeightGrid, filled: &[f32], index: uint) -> Option<uint> {
+  let mut best = None;
+  let mut best_slope = 0.0;
+
+  for k in range(0u, 8) {
+    match d8_neighbour(grid, index, k) {
+      Some(n) => {
+        let slope = (filled[index] - filled[n]) / d8_distance(k);
+        if slope > best_slope {
+          best = Some(n);
+          best_slope = slope;
+        }
+      }
+      None => {}
+    }
+  }
+  best
+}
+
+// Tarboton's D-infinity: the steepest downslope direction over the eight
+// triangular facets around the cell, with the flow split between the two
+// neighbours bounding that facet in proportion to the angle. Returns the
+// receiving cells and their shares.
+fn dinf_receivers(grid: &HeightGrid, filled: &[f32], index: uint) -> ~[(uint, f32)] {
+  let facet_angle = std::f32::consts::PI / 4.0;
+  let mut best = ~[];
+  let mut best_slope = 0.0;
+
+  // Facet f lies between a cardinal and a diagonal neighbour
+  for f in range(0u, 8) {
+    let cardinal = (f + 1) / 2 * 2 % 8;
+    let diagonal = f | 1;
+
+    match (d8_neighbour(grid, index, cardinal), d8_neighbour(grid, index, diagonal)) {
+      (Some(e1), Some(e2)) => {
+        let s1 = filled[index] - filled[e1];
+        let s2 = filled[e1] - filled[e2];
+        let mut r = s2.atan2(&s1);
+        let mut s = (s1 * s1 + s2 * s2).sqrt();
+
+        if r < 0.0 {
+          r = 0.0;
+          s = s1;
+        } else if r > facet_angle {
+          r = facet_angle;
+          s = (filled[index] - filled[e2]) / std::f32::consts::SQRT2;
+        }
+
+        if s > best_slope {
+          let share = r / facet_angle;
+          best = ~[(e1, 1.0 - share), (e2, share)];
+          best_slope = s;
+        }
+      }
+      _ => {}
+    }
+  }
+  best
+}
+
+// Fills depressions, then routes flow by the given method to find how many
+// cells drain through each. Watersheds always follow the D8 directions.
+fn analyze_drainage(grid: &HeightGrid, method: FlowMethod) -> Drainage {
+  let (filled, order) = fill_depressions(grid);
+  let downstream: ~[Option<uint>] = range(0, filled.len()).map(|i| d8_downstream(grid, filled, i)).collect();
+
+  // Every receiver is lower, so it is visited after the cells draining to it
+  let mut accumulation = vec::from_elem(filled.len(), 1.0f32);
+  for &i in order.iter().rev() {
+    match method {
+      D8 => match downstream[i] {
+        Some(n) => accumulation[n] += accumulation[i],
+        None => {}
+      },
+      DInfinity => for &(n, share) in dinf_receivers(grid, filled, i).iter() {
+        accumulation[n] += accumulation[i] * share;
+      }
+    }
+  }
+
+  // Outlets start a basin that everything upstream of them joins
+  let mut basins = vec::from_elem(filled.len(), 0u);
+  let mut num_basins = 0;
+  for &i in order.iter() {
+    basins[i] = match downstream[i] {
+      Some(n) => basins[n],
+      None => { num_basins += 1; num_basins - 1 }
+    };
+  }
+
+  Drainage {
+    filled:       filled,
+    order:        order,
+    downstream:   downstream,
+    accumulation: accumulation,
+    basins:       basins,
+    num_basins:   num_basins
+  }
+}
+
+// Streams are the cells draining at least threshold cells, traced down the D8
+// directions and split at confluences. With D-infinity the accumulation can
+// fall below the threshold along the D8 path, which ends the stream there.
+fn extract_rivers(grid: &HeightGrid, drainage: &Drainage, threshold: f32) -> ~[River] {
+  let is_stream = |i: uint| drainage.accumulation[i] >= threshold;
+
+  let mut inflows = vec::from_elem(drainage.filled.len(), 0u);
+  for i in range(0, drainage.filled.len()) {
+    match drainage.downstream[i] {
+      Some(n) if is_stream(i) && is_stream(n) => inflows[n] += 1,
+      _ => {}
+    }
+  }
+
+  let point = |i: uint| Vec2::new((i / grid.width as uint) as f32, (i % grid.width as uint) as f32);
+  let mut rivers = ~[];
+
+  for i in range(0, drainage.filled.len()) {
+    if !is_stream(i) || inflows[i] == 1 { continue }
+
+    let mut points = ~[point(i)];
+    let mut last = i;
+    loop {
+      match drainage.downstream[last] {
+        Some(n) if is_stream(n) => {
+          points.push(point(n));
+          last = n;
+          if inflows[n] > 1 { break }
+        }
+        _ => break
+      }
+    }
+
+    if points.len() > 1 {
+      rivers.push(River { points: points, accumulation: drainage.accumulation[last], basin: drainage.basins[i] });
+    }
+  }
+  rivers
+}
+
+fn write_rivers_geojson(rivers: &[River], file_path: &str) {
+  let features: ~[(~str, &[Vec2<f32>])] = rivers.iter().map(|river|
+    (format!("\"accumulation\":{},\"basin\":{}", river.accumulation, river.basin), river.points.as_slice())).collect();
+  write_line_features(features, file_path);
+}
+
+// Flow accumulation on a log scale, from 1 cell to the outlet of the largest basin
+fn write_accumulation(grid: &HeightGrid, drainage: &Drainage, bit_depth: uint, file_path: &str) {
+  let max = drainage.accumulation.iter().fold(2.0f32, |a, &b| a.max(b)).ln();
+  let values: ~[f32] = drainage.accumulation.iter().map(|a| a.ln() / max).collect();
+  write_raster(grid, values, bit_depth, file_path);
+}
+
+// Stream cells in RIVER_COLOR, more opaque the more they carry, and transparent
+// elsewhere
+fn rivers_pixels(drainage: &Drainage, threshold: f32) -> ~[u8] {
+  let max = drainage.accumulation.iter().fold(threshold * 2.0, |a, &b| a.max(b));
+
+  // Transparent cells keep the color too, so it doesn't darken where filtered
+  overlay_pixels(drainage.accumulation.len(), |i| {
+    let a = drainage.accumulation[i];
+    let alpha = if a < threshold { 0.0 } else { (160.0 + 95.0 * (a / threshold).ln() / (max / threshold).ln()) / 255.0 };
+    Vec4::new(RIVER_COLOR.x, RIVER_COLOR.y, RIVER_COLOR.z, alpha)
+  })
+}
+
+// Writes the streams to rivers.geojson and the flow accumulation to
+// accumulation.png in the output directory
+fn export_drainage(grid: &HeightGrid, output_dir: &str, method: FlowMethod, threshold: f32, bit_depth: uint) {
+  let drainage = analyze_drainage(grid, method);
+  let rivers = extract_rivers(grid, &drainage, threshold);
+  if DEBUG { println!("{} basins, {} streams", drainage.num_basins, rivers.len()) }
+
+  let file_path = format!("{}/rivers.geojson", output_dir);
+  if DEBUG { println!("Writing {}", file_path) }
+  write_rivers_geojson(rivers, file_path);
+
+  let file_path = format!("{}/accumulation.png", output_dir);
+  if DEBUG { println!("Writing {}", file_path) }
+  write_accumulation(grid, &drainage, bit_depth, file_path);
+}
+
+// The drainage analysis and regions the overlays are drawn from, run when they
+// are first shown since they take a while on large grids
+struct Analysis {
+  flow_method:      FlowMethod,
+  stream_threshold: f32,
+  drainage:         Option<Drainage>,
+  rivers_drawn:     bool, // Whether the rivers texture has been filled in
+  region_seeds:     ~[Vec2<f32>], // Basins are used if there are none
+  regions:          Option<Regions>,
+  regions_drawn:    bool
+}
+
+impl Analysis {
+  fn drainage<'a>(&'a mut self, grid: &HeightGrid) -> &'a Drainage {
+    if self.drainage.is_none() {
+      if DEBUG { print!("Analyzing drainage... "); flush(); }
+      let drainage = analyze_drainage(grid, self.flow_method);
+      if DEBUG { println!("done. ({} basins)", drainage.num_basins) }
+      self.drainage = Some(drainage);
+    }
+    self.drainage.get_ref()
+  }
+
+  fn regions<'a>(&'a mut self, grid: &HeightGrid) -> &'a Regions {
+    if self.regions.is_none() {
+      let regions = if self.region_seeds.len() > 0 {
+        voronoi_regions(grid, self.region_seeds)
+      } else {
+        basin_regions(self.drainage(grid))
+      };
+      if DEBUG { println!("Segmented {} regions", regions.count) }
+      self.regions = Some(regions);
+    }
+    self.regions.get_ref()
+  }
+}
+
+// Fills in the overlay textures of the analyses whose overlays were just turned on
+unsafe fn update_analysis_overlays(analysis: &mut Analysis, scene: &Scene, grid: &HeightGrid) {
+  if rivers_enabled && !analysis.rivers_drawn {
+    let threshold = analysis.stream_threshold;
+    let pixels = rivers_pixels(analysis.drainage(grid), threshold);
+    update_overlay_texture(scene.rivers_texture_id, pixels, grid);
+    analysis.rivers_drawn = true;
+  }
+  if regions_enabled && !analysis.regions_drawn {
+    let pixels = regions_pixels(analysis.regions(grid), grid);
+    update_overlay_texture(scene.regions_texture_id, pixels, grid);
+    analysis.regions_drawn = true;
+  }
+}
+
+// Overlay textures hold an RGBA color per cell, in grid order like the lightmap:
+// a texture row per grid x, with grid y running across it. This one is
+// transparent, for an overlay filled in later with update_overlay_texture.
+unsafe fn initialize_overlay_texture(grid: &HeightGrid, filter: GLenum) -> GLuint {
+  let pixels = vec::from_elem((grid.width * grid.height * 4) as uint, 0u8);
+  let mut texture_id = 0;
+
+  gl::GenTextures(1, &mut texture_id);
+  gl::BindTexture(gl::TEXTURE_2D, texture_id);
+  gl::TexImage2D(gl::TEXTURE_2D, 0, gl::RGBA8 as GLint, grid.height as GLint, grid.width as GLint, 0, gl::RGBA, gl::UNSIGNED_BYTE, pixels.as_ptr() as GLeglImageOES);
+
+  gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, filter as i32);
+  gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, filter as i32);
+  gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
+  gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
+
+  texture_id
+}
+
+// Replaces the overlay with pixels from overlay_pixels
+unsafe fn update_overlay_texture(texture_id: GLuint, pixels: &[u8], grid: &HeightGrid) {
+  gl::BindTexture(gl::TEXTURE_2D, texture_id);
+  gl::TexSubImage2D(gl::TEXTURE_2D, 0, 0, 0, grid.height as GLint, grid.width as GLint, gl::RGBA, gl::UNSIGNED_BYTE, pixels.as_ptr() as GLeglImageOES);
+}
+
+// Overlay pixels from each cell's color, with components from 0 to 1
+fn overlay_pixels(cells: uint, color: |uint| -> Vec4<f32>) -> ~[u8] {
+  let mut pixels: ~[u8] = ~[];
+  for i in range(0, cells) {
+    let c = color(i);
+    pixels.push_all([(c.x * 255.0) as u8, (c.y * 255.0) as u8, (c.z * 255.0) as u8, (c.w * 255.0) as u8]);
+  }
+  pixels
+}
+
+// Regions  -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- --
+
+// Loads Voronoi seeds from a text file with one cell per line as "x y", the
+// cell coordinates the HUD shows under the cursor. Blank lines and lines
+// starting with '#' are ignored.
+fn load_region_seeds(file_path: &str, grid: &HeightGrid) -> ~[Vec2<f32>] {
+  let src = load_text_file(file_path);
+  let mut seeds = ~[];
+
+  for (n, line) in src.lines().enumerate() {
+    let line = line.trim();
+    if line.len() == 0 || line.starts_with("#") { continue }
+
+    let fields: ~[Option<f32>] = line.words().map(|word| from_str::<f32>(word)).collect();
+    match fields.as_slice() {
+      [Some(x), Some(y)] if grid.contains(x, y) => seeds.push(Vec2::new(x, y)),
+      [Some(_), Some(_)] => fail!("{}:{}: seed outside the terrain", file_path, n + 1),
+      _ => fail!("{}:{}: expected a cell as \"x y\"", file_path, n + 1)
+    }
+  }
+
+  if seeds.len() == 0 {
+    fail!("{}: no seeds", file_path);
+  }
+  seeds
+}
+
+// The watershed basins of the drainage analysis
+fn basin_regions(drainage: &Drainage) -> Regions {
+  Regions { labels: drainage.basins.clone(), count: drainage.num_basins }
+}
+
+// Every cell belongs to the nearest seed, numbered in the order the seeds were given
+fn voronoi_regions(grid: &HeightGrid, seeds: &[Vec2<f32>]) -> Regions {
+  let mut labels = ~[];
+  for x in range(0, grid.width) {
+    for y in range(0, grid.height) {
+      let cell = Vec2::new(x as f32, y as f32);
+      let mut nearest = 0;
+      for (i, seed) in seeds.iter().enumerate() {
+        if (cell - *seed).length2() < (cell - seeds[nearest]).length2() { nearest = i }
+      }
+      labels.push(nearest);
+    }
+  }
+  Regions { labels: labels, count: seeds.len() }
+}
+
+// Colors far apart in hue for consecutive labels, stepping by the golden ratio
+fn region_color(label: uint) -> Vec3<f32> {
+  let (saturation, value) = (0.55, 0.9);
+  let hue = (label as f32 * 0.618034) % 1.0 * 6.0;
+  let f = hue - hue.floor();
+
+  let p = value * (1.0 - saturation);
+  let q = value * (1.0 - saturation * f);
+  let t = value * (1.0 - saturation * (1.0 - f));
+  match hue as uint {
+    0 => Vec3::new(value, t, p),
+    1 => Vec3::new(q, value, p),
+    2 => Vec3::new(p, value, t),
+    3 => Vec3::new(p, q, value),
+    4 => Vec3::new(t, p, value),
+    _ => Vec3::new(value, p, q)
+  }
+}
+
+// Whether a cell touches another region along a grid line
+fn is_region_border(grid: &HeightGrid, regions: &Regions, index: uint) -> bool {
+  range_step(0u, 8, 2).any(|k| match d8_neighbour(grid, index, k) {
+    Some(n) => regions.labels[n] != regions.labels[index],
+    None => false
+  })
+}
+
+// The labels as an indexed PNG whose palette holds the overlay's region colors.
+// A palette has at most 256 entries, so past that
+// the labels are written as 16 bit grayscale samples instead. Basins often
+// number more, since every stretch of the edge the terrain drains off through
+// is a basin of its own.
+fn write_region_labels(grid: &HeightGrid, regions: &Regions, file_path: &str) {
+  if regions.count > 65536 {
+    fail!("{}: {} regions are too many to label with 16 bit samples", file_path, regions.count);
+  }
+  if regions.count > 256 {
+    println!("Warning: {} regions don't fit in a palette, writing {} as 16 bit grayscale", regions.count, file_path);
+    let samples: ~[u16] = regions.labels.iter().map(|&label| label as u16).collect();
+    write_gray_png(grid, samples, 16, file_path);
+    return
+  }
+
+  let palette: ~[Vec3<f32>] = range(0, regions.count).map(|label| region_color(label)).collect();
+  let indices: ~[u8] = regions.labels.iter().map(|&label| label as u8).collect();
+  write_indexed_png(grid, indices, palette, file_path);
+}
+
+// The png crate can't write a palette, so indexed images are encoded here. The
+// image data is left uncompressed, in stored deflate blocks.
+fn write_indexed_png(grid: &HeightGrid, indices: &[u8], palette: &[Vec3<f32>], file_path: &str) {
+  let (width, height) = (grid.height as uint, grid.width as uint);
+
+  let mut header = ~[];
+  push_u32_be(&mut header, width as u32);
+  push_u32_be(&mut header, height as u32);
+  header.push_all([8, 3, 0, 0, 0]); // 8 bit indices, palette color, no interlacing
+
+  let mut colors = ~[];
+  for color in palette.iter() {
+    colors.push_all([(color.x * 255.0) as u8, (color.y * 255.0) as u8, (color.z * 255.0) as u8]);
+  }
+
+  // Every row starts with filter type 0, none
+  let mut rows = ~[];
+  for row in indices.chunks(width) {
+    rows.push(0u8);
+    rows.push_all(row);
+  }
+
+  let mut png = ~[0x89u8, 'P' as u8, 'N' as u8, 'G' as u8, 0x0d, 0x0a, 0x1a, 0x0a];
+  push_png_chunk(&mut png, "IHDR", header);
+  push_png_chunk(&mut png, "PLTE", colors);
+  push_png_chunk(&mut png, "IDAT", zlib_stored(rows));
+  push_png_chunk(&mut png, "IEND", []);
+
+  match File::create(&Path::new(file_path)).write(png) {
+    Ok(_) => {},
+    Err(s) => fail!("{}: {}", file_path, s)
+  }
+}
+
+fn push_u32_be(bytes: &mut ~[u8], value: u32) {
+  bytes.push_all([(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8]);
+}
+
+// Length, type, data and the CRC of type and data
+fn push_png_chunk(png: &mut ~[u8], kind: &str, data: &[u8]) {
+  let mut body = kind.as_bytes().to_owned();
+  body.push_all(data);
+
+  push_u32_be(png, data.len() as u32);
+  png.push_all(body);
+  push_u32_be(png, crc32(body));
+}
+
+// The CRC-32 PNG chunks end with
+fn crc32(bytes: &[u8]) -> u32 {
+  let mut crc = 0xffffffffu32;
+  for &byte in bytes.iter() {
+    crc ^= byte as u32;
+    for _ in range(0, 8) {
+      crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+    }
+  }
+  !crc
+}
+
+// A zlib stream holding the bytes in uncompressed deflate blocks of up to 65535 bytes
+fn zlib_stored(bytes: &[u8]) -> ~[u8] {
+  let mut stream = ~[0x78u8, 0x01];
+  let blocks: ~[&[u8]] = if bytes.len() == 0 { ~[bytes] } else { bytes.chunks(65535).collect() };
+
+  for (i, block) in blocks.iter().enumerate() {
+    let last = i + 1 == blocks.len();
+    let len = block.len() as u16;
+    stream.push_all([last as u8, len as u8, (len >> 8) as u8, !len as u8, (!len >> 8) as u8]);
+    stream.push_all(*block);
+  }
+
+  // Adler-32 of the uncompressed bytes
+  let (mut a, mut b) = (1u32, 0u32);
+  for &byte in bytes.iter() {
+    a = (a + byte as u32) % 65521;
+    b = (b + a) % 65521;
+  }
+  push_u32_be(&mut stream, (b << 16) | a);
+  stream
+}
+
+// Region colors at REGION_OPACITY with opaque borders
+fn regions_pixels(regions: &Regions, grid: &HeightGrid) -> ~[u8] {
+  overlay_pixels(regions.labels.len(), |i| {
+    let (color, alpha) = if is_region_border(grid, regions, i) {
+      (REGION_BORDER_COLOR, 1.0)
+    } else {
+      (region_color(regions.labels[i]), REGION_OPACITY)
+    };
+    Vec4::new(color.x, color.y, color.z, alpha)
+  })
+}
+
+// Viewshed  -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- --
+
+// Which cells within range can be seen from eye height above the observer's
+// cell. Rays are swept from the observer to every cell on the border of the
+// square around the range, one cell per step, and a cell is visible if it
+// rises to the steepest slope seen so far along some ray. That visits each
+// cell a few times instead of testing a line of sight to every one of them.
+fn compute_viewshed(grid: &HeightGrid, observer: (u32, u32), eye_height: f32, max_range: f32) -> Viewshed {
+  let (ox, oy) = observer;
+  let eye = grid.get(ox, oy) + eye_height;
+  let origin = Vec2::new(ox as f32, oy as f32);
+  let mut cells = vec::from_elem((grid.width * grid.height) as uint, None);
+  cells[(ox * grid.width + oy) as uint] = Some(true);
+
+  let r = max_range.ceil() as int;
+  let mut ends = ~[];
+  for k in range(-r, r) {
+    ends.push_all([(-r, k), (k, r), (r, -k), (-k, -r)]);
+  }
+
+  for &(ex, ey) in ends.iter() {
+    let delta = Vec2::new(ex as f32, ey as f32);
+    let steps = std::cmp::max(ex.abs(), ey.abs());
+    let mut horizon = -std::f32::INFINITY;
+
+    for i in range(1, steps + 1) {
+      let offset = delta.mul_s(i as f32 / steps as f32);
+      let (x, y) = ((origin.x + offset.x).round(), (origin.y + offset.y).round());
+      if !grid.contains(x, y) { break }
+
+      let distance = (Vec2::new(x, y) - origin).length();
+      if distance > max_range { break }
+
+      let (x, y) = (x as u32, y as u32);
+      let slope = (grid.get(x, y) - eye) / distance;
+      let index = (x * grid.width + y) as uint;
+      cells[index] = Some(cells[index] == Some(true) || slope >= horizon);
+      horizon = horizon.max(slope);
+    }
+  }
+
+  // Rounding can leave a cell off every ray, so those are tested on their own
+  for x in range(0, grid.width) {
+    for y in range(0, grid.height) {
+      let index = (x * grid.width + y) as uint;
+      let distance = (Vec2::new(x as f32, y as f32) - origin).length();
+      if cells[index].is_none() && distance <= max_range {
+        cells[index] = Some(line_of_sight(grid, origin, eye, x, y));
+      }
+    }
+  }
+
+  let visible = cells.iter().count(|&cell| cell == Some(true));
+  Viewshed { cells: cells, visible: visible }
+}
+
+// Whether the ground at x, y is in sight from eye elevation above from. The
+// terrain in between is sampled about once per cell, and any point rising
+// above the line to the target hides it.
+fn line_of_sight(grid: &HeightGrid, from: Vec2<f32>, eye: f32, x: u32, y: u32) -> bool {
+  let delta = Vec2::new(x as f32, y as f32) - from;
+  let distance = delta.length();
+  if distance < 1.0 { return true }
+
+  let target = (grid.get(x, y) - eye) / distance;
+  let steps = distance.ceil() as uint;
+
+  for i in range(1, steps) {
+    let t = i as f32 / steps as f32;
+    let p = from + delta.mul_s(t);
+    if (grid.sample(p.x, p.y) - eye) / (distance * t) > target { return false }
+  }
+  true
+}
+
+// Visible cells in VIEWSHED_VISIBLE_COLOR and hidden ones within range in
+// VIEWSHED_HIDDEN_COLOR, and transparent beyond the range
+fn viewshed_pixels(viewshed: &Viewshed) -> ~[u8] {
+  overlay_pixels(viewshed.cells.len(), |i| match viewshed.cells[i] {
+    Some(true)  => VIEWSHED_VISIBLE_COLOR,
+    Some(false) => VIEWSHED_HIDDEN_COLOR,
+    None        => Vec4::new(0.0, 0.0, 0.0, 0.0)
+  })
+}
+
+// 255 where visible and 0 elsewhere
+fn write_viewshed_mask(grid: &HeightGrid, viewshed: &Viewshed, file_path: &str) {
+  let samples: ~[u16] = viewshed.cells.iter().map(|&cell| if cell == Some(true) { 255u16 } else { 0 }).collect();
+  write_gray_png(grid, samples, 8, file_path);
+}
+
+// Terrain statistics  -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- --
+
+// Summary of the filtered height grid the viewer renders, see terrain_stats
+struct TerrainStats {
+  cells:     uint,
+  min:       f32,
+  max:       f32,
+  mean:      f32,
+  std_dev:   f32,
+  histogram: ~[uint], // STATS_HISTOGRAM_BINS equal ranges from min to max
+
+  // Fraction of the area at or above min + i / (HYPSOMETRY_POINTS - 1) of the
+  // relief, and the area under that curve
+  hypsometry:           ~[f32],
+  hypsometric_integral: f32,
+
+  sea_level:       f32,
+  below_sea_level: f32, // Fraction of the area
+  slope_classes:   ~[uint] // Cells per SLOPE_CLASSES class
+}
+
+fn terrain_stats(grid: &HeightGrid, level: f32) -> TerrainStats {
+  let heights = grid.heights.as_slice();
+  let cells = heights.len();
+
+  let min = heights.iter().fold(std::f32::INFINITY, |a, &b| a.min(b));
+  let max = heights.iter().fold(-std::f32::INFINITY, |a, &b| a.max(b));
+  let mean = heights.iter().fold(0.0, |a, &b| a + b) / cells as f32;
+  let variance = heights.iter().fold(0.0, |a, &b| a + (b - mean) * (b - mean)) / cells as f32;
+  let relief = max - min;
+
+  // Flat terrain falls entirely in the first bin
+  let mut histogram = vec::from_elem(STATS_HISTOGRAM_BINS, 0u);
+  for &h in heights.iter() {
+    let bin = if relief > 0.0 { ((h - min) / relief * STATS_HISTOGRAM_BINS as f32) as uint } else { 0 };
+    histogram[bin.min(STATS_HISTOGRAM_BINS - 1)] += 1;
+  }
+
+  let hypsometry: ~[f32] = range(0, HYPSOMETRY_POINTS).map(|i| {
+    let threshold = min + relief * i as f32 / (HYPSOMETRY_POINTS - 1) as f32;
+    heights.iter().count(|&h| h >= threshold) as f32 / cells as f32
+  }).collect();
+
+  let mut slope_classes = vec::from_elem(SLOPE_CLASSES.len(), 0u);
+  for x in range(0, grid.width) {
+    for y in range(0, grid.height) {
+      let slope = grid.slope(x, y);
+      let class = SLOPE_CLASSES.iter().position(|&bound| slope <= bound).unwrap_or(SLOPE_CLASSES.len() - 1);
+      slope_classes[class] += 1;
+    }
+  }
+
+  TerrainStats {
+    cells:     cells,
+    min:       min,
+    max:       max,
+    mean:      mean,
+    std_dev:   variance.sqrt(),
+    histogram: histogram,
+
+    hypsometry:           hypsometry,
+    hypsometric_integral: if relief > 0.0 { (mean - min) / relief } else { 0.0 },
+
+    sea_level:       level,
+    below_sea_level: heights.iter().count(|&h| h < level) as f32 / cells as f32,
+    slope_classes:   slope_classes
+  }
+}
+
+fn print_stats(stats: &TerrainStats) {
+  let relief = stats.max - stats.min;
+  let bin_size = relief / STATS_HISTOGRAM_BINS as f32;
+  let largest_bin = stats.histogram.iter().fold(1, |a, &b| a.max(b));
+
+  println!("Cells: {}", stats.cells);
+  println!("Elevation: min {:.2f}, max {:.2f}, mean {:.2f}, std dev {:.2f}", stats.min, stats.max, stats.mean, stats.std_dev);
+  println!("Below sea level ({:.1f}): {:.1f}%", stats.sea_level, stats.below_sea_level * 100.0);
+
+  println!("\nHistogram:");
+  for (i, &count) in stats.histogram.iter().enumerate() {
+    let low = stats.min + bin_size * i as f32;
+    println!("  {:8.2f} - {:8.2f} {:8u} {}", low, low + bin_size, count, "#".repeat(count * 40 / largest_bin));
+  }
+
+  println!("\nHypsometric curve (relative elevation, area above):");
+  for (i, &area) in stats.hypsometry.iter().enumerate() {
+    println!("  {:4.2f} {:6.3f}", i as f32 / (HYPSOMETRY_POINTS - 1) as f32, area);
+  }
+  println!("  Integral: {:.3f}", stats.hypsometric_integral);
+
+  println!("\nSlope:");
+  let mut lower = 0.0;
+  for (&bound, &count) in SLOPE_CLASSES.iter().zip(stats.slope_classes.iter()) {
+    println!("  {:4.1f} - {:4.1f} degrees {:6.2f}%", lower, bound, count as f32 / stats.cells as f32 * 100.0);
+    lower = bound;
+  }
+}
+
+fn json_array<T: std::fmt::Show>(values: &[T]) -> ~str {
+  let items: ~[~str] = values.iter().map(|v| format!("{}", *v)).collect();
+  "[".to_owned() + items.connect(",") + "]"
+}
+
+fn stats_json(stats: &TerrainStats) -> ~str {
+  let fields = [
+    format!("\"cells\":{}", stats.cells),
+    format!("\"min\":{}", stats.min),
+    format!("\"max\":{}", stats.max),
+    format!("\"mean\":{}", stats.mean),
+    format!("\"std_dev\":{}", stats.std_dev),
+    format!("\"histogram\":{}", json_array(stats.histogram)),
+    format!("\"hypsometry\":{}", json_array(stats.hypsometry)),
+    format!("\"hypsometric_integral\":{}", stats.hypsometric_integral),
+    format!("\"sea_level\":{}", stats.sea_level),
+    format!("\"below_sea_level\":{}", stats.below_sea_level),
+    format!("\"slope_class_bounds\":{}", json_array(SLOPE_CLASSES)),
+    format!("\"slope_classes\":{}", json_array(stats.slope_classes))
+  ];
+  "{\n  ".to_owned() + fields.connect(",\n  ") + "\n}\n"
+}
+
+// Picking  -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- --
+
+// Unprojects a window position through P * V * M, returning the ray origin on
+// the near plane and the direction to the far plane, both in model space.
+unsafe fn unproject_cursor(window: &glfw::Window, x: f64, y: f64) -> Option<(Vec3<f32>, Vec3<f32>)> {
+  let (window_width, window_height) = window.get_size();
+
+  let ndc_x = (2.0 * x / window_width as f64 - 1.0) as f32;
+  let ndc_y = (1.0 - 2.0 * y / window_height as f64) as f32;
+
+  let mvp = screen.projection_matrix.mul_m(&camera.view_matrix).mul_m(&world.model_matrix);
+
+  match mvp.invert() {
+    Some(inverse) => {
+      let near = inverse.mul_v(&Vec4::new(ndc_x, ndc_y, -1.0, 1.0));
+      let far  = inverse.mul_v(&Vec4::new(ndc_x, ndc_y,  1.0, 1.0));
+
+      let origin = Vec3::new(near.x / near.w, near.y / near.w, near.z / near.w);
+      let target = Vec3::new(far.x / far.w, far.y / far.w, far.z / far.w);
+
+      Some((origin, target - origin))
+    }
+    None => None
+  }
+}
+
+// Two-sided Moller-Trumbore ray/triangle test, returning the ray parameter
+fn intersect_triangle(origin: Vec3<f32>, dir: Vec3<f32>, a: Vec3<f32>, b: Vec3<f32>, c: Vec3<f32>) -> Option<f32> {
+  let e1 = b - a;
+  let e2 = c - a;
+  let p = dir.cross(&e2);
+  let det = e1.dot(&p);
+
+  if det.abs() < 1e-8 { return None }
+
+  let s = origin - a;
+  let u = s.dot(&p) / det;
+  if u < 0.0 || u > 1.0 { return None }
+
+  let q = s.cross(&e1);
+  let v = dir.dot(&q) / det;
+  if v < 0.0 || u + v > 1.0 { return None }
+
+  Some(e2.dot(&q) / det)
+}
+
+// Intersects the two triangles of a grid cell, split the same way as in initialize_indices
+fn intersect_cell(grid: &HeightGrid, origin: Vec3<f32>, dir: Vec3<f32>, x: u32, y: u32) -> Option<f32> {
+  let p00 = grid.position(x, y);
+  let p01 = grid.position(x, y + 1);
+  let p10 = grid.position(x + 1, y);
+  let p11 = grid.position(x + 1, y + 1);
+
+  let t1 = intersect_triangle(origin, dir, p00, p01, p10);
+  let t2 = intersect_triangle(origin, dir, p01, p11, p10);
+
+  match (t1, t2) {
+    (Some(a), Some(b)) => Some(if a < b {a} else {b}),
+    (Some(a), None)    => Some(a),
+    (None, Some(b))    => Some(b),
+    (None, None)       => None
+  }
+}
+
+// Casts a model space ray (parameterized over [0, 1]) against the height grid.
+// The ray's footprint is walked cell by cell with a 2D DDA, so only the cells
+// it actually crosses are tested, nearest first.
+fn pick_height_grid(grid: &HeightGrid, origin: Vec3<f32>, dir: Vec3<f32>) -> Option<(u32, u32, f32)> {
+  let max_x = (grid.width - 1) as f32;
+  let max_y = (grid.height - 1) as f32;
+
+  // Clip the ray against the grid bounds (slab test in x and y)
+  let mut t_enter = 0.0f32;
+  let mut t_exit = 1.0f32;
+
+  for &(o, d, max) in [(origin.x, dir.x, max_x), (origin.y, dir.y, max_y)].iter() {
+    if d.abs() < 1e-8 {
+      if o < 0.0 || o > max { return None }
+    } else {
+      let t0 = (0.0 - o) / d;
+      let t1 = (max - o) / d;
+      t_enter = t_enter.max(t0.min(t1));
+      t_exit = t_exit.min(t0.max(t1));
+    }
+  }
+
+  if t_enter > t_exit { return None }
+
+  let start = origin + dir.mul_s(t_enter);
+  let mut x = (start.x.floor() as i64).max(0).min(grid.width as i64 - 2);
+  let mut y = (start.y.floor() as i64).max(0).min(grid.height as i64 - 2);
+
+  let step_x = if dir.x > 0.0 {1} else {-1};
+  let step_y = if dir.y > 0.0 {1} else {-1};
+
+  // Ray parameter at which the next cell boundary is crossed in x and y
+  let next_boundary = |cell: i64, step: i64, o: f32, d: f32| -> f32 {
+    if d.abs() < 1e-8 {
+      std::f32::INFINITY
+    } else {
+      let boundary = if step > 0 {(cell + 1) as f32} else {cell as f32};
+      (boundary - o) / d
+    }
+  };
+
+  let mut t_max_x = next_boundary(x, step_x, origin.x, dir.x);
+  let mut t_max_y = next_boundary(y, step_y, origin.y, dir.y);
+  let t_delta_x = if dir.x.abs() < 1e-8 {std::f32::INFINITY} else {1.0 / dir.x.abs()};
+  let t_delta_y = if dir.y.abs() < 1e-8 {std::f32::INFINITY} else {1.0 / dir.y.abs()};
+
+  loop {
+    match intersect_cell(grid, origin, dir, x as u32, y as u32) {
+      Some(t) if t >= 0.0 && t <= 1.0 => return Some((x as u32, y as u32, t)),
+      _ => {}
+    }
+
+    if t_max_x.min(t_max_y) > t_exit { return None }
+
+    if t_max_x < t_max_y {
+      x += step_x;
+      t_max_x += t_delta_x;
+    } else {
+      y += step_y;
+      t_max_y += t_delta_y;
+    }
+
+    if x < 0 || y < 0 || x > grid.width as i64 - 2 || y > grid.height as i64 - 2 { return None }
+  }
+}
+
+// Recomputes what lies under the cursor whenever the cursor or camera moved.
+// The result is shown in the HUD.
+unsafe fn update_cursor_pick(window: &glfw::Window, grid: &HeightGrid) {
+  if !cursor_moved { return }
+  cursor_moved = false;
+
+  let (x, y) = cursor_pos;
+
+  cursor_pick = match unproject_cursor(window, x, y) {
+    Some((origin, dir)) => match pick_height_grid(grid, origin, dir) {
+      Some((cx, cy, t)) => {
+        let position = origin + dir.mul_s(t);
+        let world_pos = world.model_matrix.mul_v(&Vec4::new(position.x, position.y, position.z, 1.0));
+
+        Some(Pick {
+          cell:      (cx, cy),
+          position:  position,
+          world:     Vec3::new(world_pos.x, world_pos.y, world_pos.z),
+          elevation: -position.z
+        })
+      }
+      None => None
+    },
+    None => None
+  };
+}
+
+// Input recording  -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- --
+
+// Only events that change what is rendered are recorded and replayed
+fn is_replayed_event((_, event): (f64, glfw::WindowEvent)) -> bool {
+  match event {
+    glfw::KeyEvent(..)       => true,
+    glfw::CursorPosEvent(..) => true,
+    glfw::SizeEvent(..)      => true,
+    _                        => false
+  }
+}
+
+// Whether the event presses a key bound to Quit
+fn is_quit_event(bindings: &[KeyBinding], (_, event): (f64, glfw::WindowEvent)) -> bool {
+  match event {
+    glfw::KeyEvent(key, _, glfw::Press, mods) => matching_bindings(bindings, key, mods).iter().any(|b| b.action == Quit),
+    _ => false
+  }
+}
+
+fn action_name(action: glfw::Action) -> &'static str {
+  match action {
+    glfw::Press   => "press",
+    glfw::Repeat  => "repeat",
+    glfw::Release => "release"
+  }
+}
+
+// Writes one event per line as "frame time kind arguments...", e.g.
+//
+//   120 2.0451 key Shift+Up 111 press
+//   121 2.0612 cursor 640.5 318
+//   130 2.2203 size 1280 800
+fn record_event(file: &mut File, frame: u64, (time, event): (f64, glfw::WindowEvent)) {
+  let line = match event {
+    // Keys without a name, e.g. modifiers pressed on their own, can't be bound
+    // to anything, so leaving them out doesn't change the replay
+    glfw::KeyEvent(key, _, _, _) if key_name(key).is_none() => return,
+    glfw::KeyEvent(key, scancode, action, mods) =>
+      format!("{} {} key {} {} {}", frame, time, key_combination_name(key, mods), scancode, action_name(action)),
+    glfw::CursorPosEvent(x, y) =>
+      format!("{} {} cursor {} {}", frame, time, x, y),
+    glfw::SizeEvent(w, h) =>
+      format!("{} {} size {} {}", frame, time, w, h),
+    _ => return
+  };
+
+  match file.write_line(line) {
+    Ok(()) => {},
+    Err(s) => fail!(s)
+  }
+}
+
+fn malformed_event(file_path: &str, line_number: uint, line: &str) -> ! {
+  fail!("{}:{}: malformed event '{}'", file_path, line_number, line)
+}
+
+fn load_recording(file_path: &str) -> ~[RecordedEvent] {
+  let src = load_text_file(file_path);
+  let mut events: ~[RecordedEvent] = ~[];
+
+  for (n, line) in src.lines().enumerate() {
+    let line = line.trim();
+    if line.len() == 0 || line.starts_with("#") { continue }
+
+    let fields: ~[&str] = line.words().collect();
+
+    if fields.len() < 3 { malformed_event(file_path, n + 1, line) }
+
+    let frame = match from_str::<u64>(fields[0]) { Some(f) => f, None => malformed_event(file_path, n + 1, line) };
+    let time = match from_str::<f64>(fields[1]) { Some(t) => t, None => malformed_event(file_path, n + 1, line) };
+
+    let event = match (fields[2], fields.len()) {
+      ("key", 6) => {
+        let (key, mods) = match parse_key_combination(fields[3]) { Some(k) => k, None => malformed_event(file_path, n + 1, line) };
+        let scancode = match from_str::<i32>(fields[4]) { Some(s) => s, None => malformed_event(file_path, n + 1, line) };
+        let action = match fields[5] {
+          "press"   => glfw::Press,
+          "repeat"  => glfw::Repeat,
+          "release" => glfw::Release,
+          _         => malformed_event(file_path, n + 1, line)
+        };
+        glfw::KeyEvent(key, scancode, action, mods)
+      }
+      ("cursor", 5) => match (from_str::<f64>(fields[3]), from_str::<f64>(fields[4])) {
+        (Some(x), Some(y)) => glfw::CursorPosEvent(x, y),
+        _ => malformed_event(file_path, n + 1, line)
+      },
+      ("size", 5) => match (from_str::<i32>(fields[3]), from_str::<i32>(fields[4])) {
+        (Some(w), Some(h)) => glfw::SizeEvent(w, h),
+        _ => malformed_event(file_path, n + 1, line)
+      },
+      _ => malformed_event(file_path, n + 1, line)
+    };
+
+    events.push(RecordedEvent { frame: frame, time: time, event: event });
+  }
+  events
+}
+
+// Key bindings -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- --
+
+// Names used for actions in binding files and the help overlay
+static KEY_ACTION_NAMES: &'static [(&'static str, KeyAction)] = &[
+  ("quit",              Quit),
+  ("move_north",        MoveNorth),
+  ("move_south",        MoveSouth),
+  ("move_west",         MoveWest),
+  ("move_east",         MoveEast),
+  ("rotate_x_cw",       RotateXCw),
+  ("rotate_x_ccw",      RotateXCcw),
+  ("rotate_y_cw",       RotateYCw),
+  ("rotate_y_ccw",      RotateYCcw),
+  ("rotate_z_cw",       RotateZCw),
+  ("rotate_z_ccw",      RotateZCcw),
+  ("zoom_in",           ZoomIn),
+  ("zoom_out",          ZoomOut),
+  ("dim_sunlight",      DimSunlight),
+  ("brighten_sunlight", BrightenSunlight),
+  ("toggle_wireframe",  ToggleWireframe),
+  ("print_keyframe",    PrintKeyframe),
+  ("toggle_hud",        ToggleHud),
+  ("toggle_help",       ToggleHelp),
+  ("refresh_window",    RefreshWindow),
+  ("toggle_day_cycle",  ToggleDayCycle),
+  ("advance_time",      AdvanceTimeOfDay),
+  ("rewind_time",       RewindTimeOfDay),
+  ("toggle_shadows",    ToggleShadows),
+  ("toggle_lightmap",   ToggleLightmap),
+  ("raise_sea_level",   RaiseSeaLevel),
+  ("lower_sea_level",   LowerSeaLevel),
+  ("toggle_reflections", ToggleReflections),
+  ("toggle_triplanar",  ToggleTriplanar),
+  ("toggle_land_use",   ToggleLandUse),
+  ("toggle_fog",        ToggleFog),
+  ("denser_fog",        DenserFog),
+  ("thinner_fog",       ThinnerFog),
+  ("toggle_scattering", ToggleScattering),
+  ("toggle_chunk_lines", ToggleChunkLines),
+  ("cycle_debug_view",  CycleDebugView),
+  ("toggle_normal_lines", ToggleNormalLines),
+  ("toggle_flat_shading", ToggleFlatShading),
+  ("toggle_contours",   ToggleContours),
+  ("finer_contours",    FinerContours),
+  ("coarser_contours",  CoarserContours),
+  ("toggle_rivers",     ToggleRivers),
+  ("toggle_regions",    ToggleRegions),
+  ("place_observer",    PlaceObserver),
+  ("toggle_viewshed",   ToggleViewshed)
+];
+
+static KEY_NAMES: &'static [(&'static str, glfw::Key)] = &[
+  ("A", glfw::KeyA), ("B", glfw::KeyB), ("C", glfw::KeyC), ("D", glfw::KeyD),
+  ("E", glfw::KeyE), ("F", glfw::KeyF), ("G", glfw::KeyG), ("H", glfw::KeyH),
+  ("I", glfw::KeyI), ("J", glfw::KeyJ), ("K", glfw::KeyK), ("L", glfw::KeyL),
+  ("M", glfw::KeyM), ("N", glfw::KeyN), ("O", glfw::KeyO), ("P", glfw::KeyP),
+  ("Q", glfw::KeyQ), ("R", glfw::KeyR), ("S", glfw::KeyS), ("T", glfw::KeyT),
+  ("U", glfw::KeyU), ("V", glfw::KeyV), ("W", glfw::KeyW), ("X", glfw::KeyX),
+  ("Y", glfw::KeyY), ("Z", glfw::KeyZ),
+
+  ("0", glfw::Key0), ("1", glfw::Key1), ("2", glfw::Key2), ("3", glfw::Key3),
+  ("4", glfw::Key4), ("5", glfw::Key5), ("6", glfw::Key6), ("7", glfw::Key7),
+  ("8", glfw::Key8), ("9", glfw::Key9),
+
+  ("F1", glfw::KeyF1), ("F2",  glfw::KeyF2),  ("F3",  glfw::KeyF3),  ("F4",  glfw::KeyF4),
+  ("F5", glfw::KeyF5), ("F6",  glfw::KeyF6),  ("F7",  glfw::KeyF7),  ("F8",  glfw::KeyF8),
+  ("F9", glfw::KeyF9), ("F10", glfw::KeyF10), ("F11", glfw::KeyF11), ("F12", glfw::KeyF12),
+
+  ("Up", glfw::KeyUp), ("Down", glfw::KeyDown), ("Left", glfw::KeyLeft), ("Right", glfw::KeyRight),
+  ("PageUp", glfw::KeyPageUp), ("PageDown", glfw::KeyPageDown), ("Home", glfw::KeyHome), ("End", glfw::KeyEnd),
+  ("Insert", glfw::KeyInsert), ("Delete", glfw::KeyDelete),
+
+  ("Space", glfw::KeySpace), ("Escape", glfw::KeyEscape), ("Enter", glfw::KeyEnter),
+  ("Tab", glfw::KeyTab), ("Backspace", glfw::KeyBackspace),
+
+  ("Minus", glfw::KeyMinus), ("Equal", glfw::KeyEqual), ("Comma", glfw::KeyComma),
+  ("Period", glfw::KeyPeriod), ("Slash", glfw::KeySlash), ("Semicolon", glfw::KeySemicolon),
+  ("Apostrophe", glfw::KeyApostrophe), ("LeftBracket", glfw::KeyLeftBracket),
+  ("RightBracket", glfw::KeyRightBracket), ("Backslash", glfw::KeyBackslash),
+  ("GraveAccent", glfw::KeyGraveAccent)
+];
+
+// Continuous actions also fire on key repeat, toggles only on the initial press
+fn key_action_repeats(action: KeyAction) -> bool {
+  match action {
+    MoveNorth | MoveSouth | MoveWest | MoveEast |
+    RotateXCw | RotateXCcw | RotateYCw | RotateYCcw | RotateZCw | RotateZCcw |
+    ZoomIn | ZoomOut | DimSunlight | BrightenSunlight |
+    AdvanceTimeOfDay | RewindTimeOfDay | RaiseSeaLevel | LowerSeaLevel |
+    DenserFog | ThinnerFog => true,
+    _ => false
+  }
+}
+
+fn bind(key: glfw::Key, mods: glfw::Modifiers, action: KeyAction) -> KeyBinding {
+  KeyBinding { key: key, mods: mods, action: action }
+}
+
+fn default_key_bindings() -> ~[KeyBinding] {
+  let none = glfw::Modifiers::empty();
+
+  ~[
+    bind(glfw::KeyEscape, none,        Quit),
+
+    bind(glfw::KeyW,      none,        MoveNorth),
+    bind(glfw::KeyS,      none,        MoveSouth),
+    bind(glfw::KeyA,      none,        MoveWest),
+    bind(glfw::KeyD,      none,        MoveEast),
+
+    bind(glfw::KeyUp,     none,        RotateXCw),
+    bind(glfw::KeyDown,   none,        RotateXCcw),
+    bind(glfw::KeyLeft,   none,        RotateYCw),
+    bind(glfw::KeyRight,  none,        RotateYCcw),
+    bind(glfw::KeyUp,     glfw::Shift, RotateZCw),
+    bind(glfw::KeyDown,   glfw::Shift, RotateZCcw),
+
+    bind(glfw::KeyR,      none,        ZoomIn),
+    bind(glfw::KeyF,      none,        ZoomOut),
+
+    bind(glfw::KeyK,      none,        DimSunlight),
+    bind(glfw::KeyL,      none,        BrightenSunlight),
+
+    bind(glfw::KeyT,      none,        ToggleWireframe),
+    bind(glfw::KeyP,      none,        PrintKeyframe),
+    bind(glfw::KeyH,      none,        ToggleHud),
+    bind(glfw::KeyF1,     none,        ToggleHelp),
+    bind(glfw::KeySpace,  none,        RefreshWindow),
+
+    bind(glfw::KeyN,      none,        ToggleDayCycle),
+    bind(glfw::KeyPeriod, none,        AdvanceTimeOfDay),
+    bind(glfw::KeyComma,  none,        RewindTimeOfDay),
+    bind(glfw::KeyO,      none,        ToggleShadows),
+    bind(glfw::KeyG,      none,        ToggleLightmap),
+    bind(glfw::KeyPageUp, none,        RaiseSeaLevel),
+    bind(glfw::KeyPageDown, none,      LowerSeaLevel),
+    bind(glfw::KeyE,      none,        ToggleReflections),
+    bind(glfw::KeyY,      none,        ToggleTriplanar),
+    bind(glfw::KeyU,      none,        ToggleLandUse),
+    bind(glfw::KeyJ,      none,        ToggleFog),
+    bind(glfw::KeyRightBracket, none,  DenserFog),
+    bind(glfw::KeyLeftBracket, none,   ThinnerFog),
+    bind(glfw::KeyI,      none,        ToggleScattering),
+    bind(glfw::KeyB,      none,        ToggleChunkLines),
+    bind(glfw::KeyF2,     none,        CycleDebugView),
+    bind(glfw::KeyF3,     none,        ToggleNormalLines),
+    bind(glfw::KeyM,      none,        ToggleFlatShading),
+    bind(glfw::KeyC,      none,        ToggleContours),
+    bind(glfw::KeyC,      glfw::Control, FinerContours),
+    bind(glfw::KeyC,      glfw::Shift, CoarserContours),
+    bind(glfw::KeyV,      none,        ToggleRivers),
+    bind(glfw::KeyX,      none,        ToggleRegions),
+    bind(glfw::KeyInsert, none,        PlaceObserver),
+    bind(glfw::KeyF4,     none,        ToggleViewshed)
+  ]
+}
+
+fn parse_key_action(name: &str) -> Option<KeyAction> {
+  KEY_ACTION_NAMES.iter().find(|&&(n, _)| n == name).map(|&(_, action)| action)
+}
+
+// Parses a key combination such as "W", "Shift+Up" or "Ctrl+Alt+F5"
+fn parse_key_combination(combination: &str) -> Option<(glfw::Key, glfw::Modifiers)> {
+  let parts: ~[&str] = combination.split('+').map(|part| part.trim()).collect();
+  let mut mods = glfw::Modifiers::empty();
+
+  for &part in parts.slice_to(parts.len() - 1).iter() {
+    match part.to_ascii_lower().as_slice() {
+      "shift"            => mods = mods | glfw::Shift,
+      "ctrl" | "control" => mods = mods | glfw::Control,
+      "alt"              => mods = mods | glfw::Alt,
+      "super"            => mods = mods | glfw::Super,
+      _                  => return None
+    }
+  }
+
+  let name = parts[parts.len() - 1];
+  KEY_NAMES.iter()
+    .find(|&&(n, _)| n.eq_ignore_ascii_case(name))
+    .map(|&(_, key)| (key, mods))
+}
+
+fn key_combination_name(key: glfw::Key, mods: glfw::Modifiers) -> ~str {
+  let mut name = ~"";
+
+  if mods.contains(glfw::Super)   { name = name + "Super+" }
+  if mods.contains(glfw::Control) { name = name + "Ctrl+" }
+  if mods.contains(glfw::Alt)     { name = name + "Alt+" }
+  if mods.contains(glfw::Shift)   { name = name + "Shift+" }
+
+  match key_name(key) {
+    Some(key_name) => name + key_name,
+    None => name + "?"
+  }
+}
+
+fn key_name(key: glfw::Key) -> Option<&'static str> {
+  KEY_NAMES.iter().find(|&&(_, k)| k == key).map(|&(name, _)| name)
+}
+
+// Loads bindings on top of the defaults from a file with lines like
+//
+//   move_north = Z
+//   rotate_z_cw = Shift+Up
+//
+// An action listed in the file loses its default keys; listing it more than
+// once binds several keys to it. Lines starting with '#' are comments. Keys
+// are named after their place on a US keyboard, since that is what GLFW
+// reports, so on other layouts the defaults stay under the same fingers.
+fn load_key_bindings(file_path: &str) -> ~[KeyBinding] {
+  let src = load_text_file(file_path);
+  let mut overrides: ~[KeyBinding] = ~[];
+
+  for (n, line) in src.lines().enumerate() {
+    let line = line.trim();
+    if line.len() == 0 || line.starts_with("#") { continue }
+
+    let fields: ~[&str] = line.splitn('=', 1).map(|field| field.trim()).collect();
+    if fields.len() != 2 {
+      fail!("{}:{}: expected 'action = key'", file_path, n + 1);
+    }
+
+    let action = match parse_key_action(fields[0]) {
+      Some(action) => action,
+      None => fail!("{}:{}: unknown action '{}'", file_path, n + 1, fields[0])
+    };
+
+    match parse_key_combination(fields[1]) {
+      Some((key, mods)) => overrides.push(bind(key, mods, action)),
+      None => fail!("{}:{}: unknown key '{}'", file_path, n + 1, fields[1])
+    }
+  }
+
+  let mut bindings: ~[KeyBinding] = default_key_bindings().move_iter()
+    .filter(|default| !overrides.iter().any(|o| o.action == default.action))
+    .collect();
+
+  bindings.push_all_move(overrides);
+  bindings
+}
+
+// One line per action listing every key bound to it, for the help overlay
+fn bindings_help(bindings: &[KeyBinding]) -> ~str {
+  let mut help = ~"Key bindings\n";
+
+  for &(name, action) in KEY_ACTION_NAMES.iter() {
+    let keys: ~[~str] = bindings.iter()
+      .filter(|binding| binding.action == action)
+      .map(|binding| key_combination_name(binding.key, binding.mods))
+      .collect();
+
+    let keys = if keys.len() > 0 { keys.connect(", ") } else { ~"(unbound)" };
+    help = help + format!("\n{:<18s} {}", name, keys);
+  }
+  help
+}
+
+// Time of day  -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- --
+
+// The simulated day and hour, which only advance with the day/night cycle
+fn simulation_time_text() -> ~str {
+  unsafe {
+    let minutes = (time_of_day * 60.0) as uint;
+    format!("day {}, {:02u}:{:02u}{}", simulation_day, minutes / 60, minutes % 60,
+            if day_cycle { "" } else { " (day/night cycle off)" })
+  }
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+  let t = ((x - edge0) / (edge1 - edge0)).max(0.0).min(1.0);
+  t * t * (3.0 - 2.0 * t)
+}
+
+fn mix(a: Vec3<f32>, b: Vec3<f32>, t: f32) -> Vec3<f32> {
+  a + (b - a).mul_s(t)
+}
+
+unsafe fn advance_time_of_day(seconds: f32) {
+  update_time_of_day(time_of_day + seconds * 24.0 / DAY_LENGTH);
+}
+
+// Places the sun for the given hour and derives the light and sky colors from
+// its elevation. The sun rises in the east at 6:00, passes the zenith slightly
+// to the south at noon and sets in the west at 18:00. Without the day/night
+// cycle the original fixed sun is restored.
+unsafe fn update_time_of_day(hours: f32) {
+  simulation_day += (hours / 24.0).floor() as int;
+  time_of_day = (hours % 24.0 + 24.0) % 24.0;
+
+  if !day_cycle {
+    world.sunlight.direction = Vec3::new(0.2, 0.2, 0.2);
+    world.sunlight.color = Vec3::new(0.8, 1.0, 1.0);
+    world.ambient = Vec3::new(0.1, 0.1, 0.1);
+    world.sky_color = Vec3::new(34.0/256.0, 37.0/256.0, 39.0/256.0);
+    return
+  }
+
+  let angle = (time_of_day - 6.0) / 12.0 * std::f32::consts::PI;
+  let elevation = angle.sin();
+
+  world.sunlight.direction = Vec3::new(angle.cos(), 0.3, elevation).normalize();
+
+  // 0 at night, 1 once the sun is well above the horizon
+  let daylight = smoothstep(-0.1, 0.3, elevation);
+  // Strongest just around sunrise and sunset
+  let twilight = 1.0 - smoothstep(0.0, 0.35, elevation.abs());
+
+  let moon_color = Vec3::new(0.25, 0.3, 0.45);
+  let noon_color = Vec3::new(1.0, 0.97, 0.9);
+  let dusk_color = Vec3::new(1.0, 0.55, 0.3);
+
+  let sun_color = mix(noon_color, dusk_color, twilight);
+  world.sunlight.color = mix(moon_color, sun_color, daylight);
+
+  world.ambient = mix(Vec3::new(0.03, 0.035, 0.06), Vec3::new(0.12, 0.12, 0.14), daylight);
+
+  let night_sky = Vec3::new(0.02, 0.03, 0.07);
+  let day_sky = Vec3::new(0.45, 0.62, 0.85);
+  let dusk_sky = Vec3::new(0.75, 0.45, 0.35);
+
+  world.sky_color = mix(mix(night_sky, day_sky, daylight), dusk_sky, twilight * 0.6);
+}
+
+// Event handling -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- --
+
+unsafe fn update_model_matrix() {
+  world.model_matrix = Transform3D::new(world.scale, world.rotation, world.translation).to_mat4();
+}
+
+unsafe fn update_view_matrix() {
+  camera.view_matrix = Transform3D::new(camera.scale, camera.rotation, camera.translation).to_mat4();
+}
+
+unsafe fn update_projection_matrix() {
+  // screen.projection_matrix = Mat4::identity();
+}
+
+// Rotation  - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - -
+
+unsafe fn rotate_camera(x: f32, y: f32, z: f32) {
+
+  camera.rotation.v.x += x;
+  camera.rotation.v.y += y;
+  camera.rotation.v.z += z;
+
+  update_view_matrix();
+}
+
+unsafe fn rotate_x(cw: bool) {
+  let sign = if cw {1.0} else {-1.0};
+  rotate_camera(CAMERA_ROTATE_BY * sign, 0.0, 0.0);
+}
+
+unsafe fn rotate_y(cw: bool) {
+  let sign = if cw {1.0} else {-1.0};
+  rotate_camera(0.0, CAMERA_ROTATE_BY * sign, 0.0);
+}
+
+unsafe fn rotate_z(cw: bool) {
+  let sign = if cw {1.0} else {-1.0};
+  rotate_camera(0.0, 0.0, CAMERA_ROTATE_BY * sign);
+}
+
+// Translation - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - -
+
+unsafe fn translate_camera(x: f32, y: f32, z: f32) {
+  camera.translation.x += x;
+  camera.translation.y += y;
+  camera.translation.z += z;
+
+  update_view_matrix();
+}
+
+unsafe fn move(dir: Compass) {
+  match dir {
+    North => translate_camera(0f32, -CAMERA_TRANSLATE_BY, 0f32),
+    South => translate_camera(0f32,  CAMERA_TRANSLATE_BY, 0f32),
+    West  => translate_camera( CAMERA_TRANSLATE_BY, 0f32, 0f32),
+    East  => translate_camera(-CAMERA_TRANSLATE_BY, 0f32, 0f32)
+  }
+}
+
+// Scaling - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - -
+
+unsafe fn scale_camera(factor: f32) {
+  if camera.scale + factor > SCALE_MIN && camera.scale + factor < SCALE_MAX {
+    camera.scale += factor;
+  }
+
+  update_view_matrix();
+}
+
+unsafe fn zoom(dir: Zoom) {
+  match dir {
+    In  => scale_camera( CAMERA_SCALE_BY),
+    Out => scale_camera(-CAMERA_SCALE_BY)
+  }
+}
+
+unsafe fn adjust_light_intensity(factor: f32) {
+  if world.sunlight.intensity + factor > SUNLIGHT_INTENSITY_MIN
+  && world.sunlight.intensity + factor < SUNLIGHT_INTENSITY_MAX {
+    world.sunlight.intensity += factor
+  }
+}
+
+fn handle_window_event(window: &glfw::Window, hud: &mut Hud, bindings: &[KeyBinding], (time, event): (f64, glfw::WindowEvent)) {
+  unsafe {
+    match event {
+      glfw::PosEvent(x, y)                => hud.status = format!("Time: {}, Window pos: ({}, {})", time, x, y),
+      glfw::SizeEvent(w, h)               => hud.status = format!("Time: {}, Window size: ({}, {})", time, w, h),
+      glfw::CloseEvent                    => hud.status = format!("Time: {}, Window close requested.", time),
+      glfw::RefreshEvent                  => hud.status = format!("Time: {}, Window refresh callback triggered.", time),
+      glfw::FocusEvent(true)              => hud.status = format!("Time: {}, Window focus gained.", time),
+      glfw::FocusEvent(false)             => hud.status = format!("Time: {}, Window focus lost.", time),
+      glfw::IconifyEvent(true)            => hud.status = format!("Time: {}, Window was minimised", time),
+      glfw::IconifyEvent(false)           => hud.status = format!("Time: {}, Window was maximised.", time),
+      glfw::FramebufferSizeEvent(w, h)    => {
+        hud.status = format!("Time: {}, Framebuffer size: ({}, {})", time, w, h);
+        framebuffer_resize = Some((w, h));
+      }
+      glfw::CharEvent(character)          => hud.status = format!("Time: {}, Character: {}", time, character),
+      glfw::MouseButtonEvent(btn, action, mods) => hud.status = format!("Time: {}, Button: {}, Action: {}, Modifiers: [{}]", time, btn, action, mods),
+      glfw::CursorPosEvent(xpos, ypos)    => { cursor_pos = (xpos, ypos); cursor_moved = true },
+      glfw::CursorEnterEvent(true)        => hud.status = format!("Time: {}, Cursor entered window.", time),
+      glfw::CursorEnterEvent(false)       => hud.status = format!("Time: {}, Cursor left window.", time),
+      glfw::ScrollEvent(x, y)             => hud.status = format!("Time: {}, Scroll offset: ({}, {})", time, x, y),
+      glfw::KeyEvent(key, scancode, action, mods) => {
+        hud.status = format!("Time: {}, Key: {}, ScanCode: {}, Action: {}, Modifiers: [{}]", time, key, scancode, action, mods);
+        handle_key_event(window, hud, bindings, time, key, action, mods);
+        cursor_moved = true; // The camera may have moved under the cursor
+      }
+    }
+  }
+}
+
+unsafe fn handle_key_event(window: &glfw::Window, hud: &mut Hud, bindings: &[KeyBinding], time: f64,
+                           key: glfw::Key, action: glfw::Action, mods: glfw::Modifiers) {
+
+  let pressed = match action {
+    glfw::Press   => true,
+    glfw::Repeat  => true,
+    glfw::Release => false
+  };
+
+  if !pressed { return }
+
+  for binding in matching_bindings(bindings, key, mods).iter() {
+    if action == glfw::Press || key_action_repeats(binding.action) {
+      perform_key_action(window, hud, time, binding.action);
+    }
+  }
+}
+
+// Bindings whose modifiers are all held, so e.g. W still moves with Shift
+// held. Only the most specific of them apply: Shift+C coarsens the contours
+// without C toggling them as well.
+fn matching_bindings<'a>(bindings: &'a [KeyBinding], key: glfw::Key, mods: glfw::Modifiers) -> ~[&'a KeyBinding] {
+  let held: ~[&KeyBinding] = bindings.iter().filter(|b| b.key == key && mods.contains(b.mods)).collect();
+  held.iter().filter(|b| !held.iter().any(|other| other.mods != b.mods && other.mods.contains(b.mods)))
+      .map(|&b| b).collect()
+}
+
+unsafe fn perform_key_action(window: &glfw::Window, hud: &mut Hud, time: f64, action: KeyAction) {
+  match action {
+    Quit             => window.set_should_close(true),
+
+    MoveNorth        => move(North),
+    MoveSouth        => move(South),
+    MoveWest         => move(West),
+    MoveEast         => move(East),
+
+    RotateXCw        => rotate_x(true),
+    RotateXCcw       => rotate_x(false),
+    RotateYCw        => rotate_y(true),
+    RotateYCcw       => rotate_y(false),
+    RotateZCw        => rotate_z(true),
+    RotateZCcw       => rotate_z(false),
+
+    ZoomIn           => zoom(In),
+    ZoomOut          => zoom(Out),
+
+    DimSunlight      => adjust_light_intensity(-0.02),
+    BrightenSunlight => adjust_light_intensity(0.02),
+
+    ToggleWireframe  => wireframe_enabled = !wireframe_enabled,
+    PrintKeyframe    => print_camera_keyframe(time),
+    ToggleHud        => hud.visible = !hud.visible,
+    ToggleHelp       => hud.show_help = !hud.show_help,
+
+    ToggleDayCycle   => { day_cycle = !day_cycle; update_time_of_day(time_of_day) },
+    AdvanceTimeOfDay => { day_cycle = true; update_time_of_day(time_of_day + 0.25) },
+    RewindTimeOfDay  => { day_cycle = true; update_time_of_day(time_of_day - 0.25) },
+    ToggleShadows    => shadows_enabled = !shadows_enabled,
+    ToggleLightmap   => lightmap_enabled = !lightmap_enabled,
+    RaiseSeaLevel    => { let (_, highest) = sea_level_range; sea_level = (sea_level + SEA_LEVEL_STEP).min(highest) },
+    LowerSeaLevel    => { let (lowest, _) = sea_level_range; sea_level = (sea_level - SEA_LEVEL_STEP).max(lowest) },
+    ToggleReflections => reflections_enabled = !reflections_enabled,
+    ToggleTriplanar  => triplanar_enabled = !triplanar_enabled,
+    ToggleLandUse    => land_use_enabled = !land_use_enabled,
+    ToggleFog        => fog_enabled = !fog_enabled,
+    DenserFog        => fog_density += FOG_DENSITY_STEP,
+    ThinnerFog       => fog_density = (fog_density - FOG_DENSITY_STEP).max(0.0),
+    ToggleScattering => scattering_enabled = !scattering_enabled,
+    ToggleChunkLines => chunk_lines_enabled = !chunk_lines_enabled,
+    CycleDebugView   => debug_view = match debug_view {
+      NoDebugView  => NormalView,
+      NormalView   => TexcoordView,
+      TexcoordView => HeightView,
+      HeightView   => LodView,
+      LodView      => NoDebugView
+    },
+    ToggleNormalLines => normal_lines_enabled = !normal_lines_enabled,
+    ToggleFlatShading => if flat_shading_available {
+      flat_shading = !flat_shading
+    } else {
+      hud.status = format!("Flat shading needs a geometry shader ({})", GS_SRC)
+    },
+    ToggleContours   => contours_enabled = !contours_enabled,
+    FinerContours    => contour_interval = (contour_interval / 2.0).max(MIN_CONTOUR_INTERVAL),
+    CoarserContours  => contour_interval *= 2.0,
+    ToggleRivers     => rivers_enabled = !rivers_enabled,
+    ToggleRegions    => regions_enabled = !regions_enabled,
+    PlaceObserver    => match cursor_pick {
+      Some(pick) => { observer_request = Some(pick.cell); viewshed_enabled = true }
+      None => hud.status = ~"No terrain under the cursor to place the observer on"
+    },
+    ToggleViewshed   => viewshed_enabled = !viewshed_enabled,
+
+    RefreshWindow    => {
+      // Resize should cause the window to "refresh"
+      let (window_width, window_height) = window.get_size();
+      window.set_size(window_width + 1, window_height);
+      window.set_size(window_width, window_height);
+    }
+  }
+}
+
+// Tests  -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- -- --
+
+#[cfg(test)]
+mod tests {
+  use std::os;
+  use std::vec;
+  use std::io::File;
+  use glfw;
+
+  use cgmath::vector::Vec2;
+
+  use super::{HeightGrid, record_event, load_recording, trace_contour_level, terrain_stats};
+  use super::{D8, DInfinity, FILL_EPSILON, fill_depressions, d8_downstream, dinf_receivers, analyze_drainage};
+  use super::{voronoi_regions, crc32, zlib_stored, compute_viewshed};
+  use super::{terrain_rasters, LIGHTMAP_SUN_ALTITUDE};
+
+  fn square_grid(size: u32, heights: ~[f32]) -> HeightGrid {
+    assert_eq!(heights.len(), (size * size) as uint);
+    HeightGrid { width: size, height: size, heights: heights }
+  }
+
+  fn near(p: Vec2<f32>, x: f32, y: f32) -> bool {
+    (p.x - x).abs() < 1e-4 && (p.y - y).abs() < 1e-4
+  }
+
+  #[test]
+  fn recorded_events_load_back() {
+    let path = os::tmpdir().join("econsim-recording-test.txt");
+    {
+      let mut file = File::create(&path).unwrap();
+      record_event(&mut file, 0, (0.5, glfw::KeyEvent(glfw::KeyW, 17, glfw::Press, glfw::Shift)));
+      record_event(&mut file, 1, (0.75, glfw::KeyEvent(glfw::KeyLeftShift, 42, glfw::Press, glfw::Shift)));
+      record_event(&mut file, 2, (1.0, glfw::CursorPosEvent(10.0, 20.5)));
+      record_event(&mut file, 3, (1.25, glfw::SizeEvent(800, 600)));
+    }
+
+    let events = load_recording(path.as_str().unwrap());
+    assert_eq!(events.len(), 3);
+
+    assert_eq!((events[0].frame, events[0].time), (0, 0.5));
+    match events[0].event {
+      glfw::KeyEvent(key, scancode, glfw::Press, mods) => {
+        assert!(key == glfw::KeyW);
+        assert_eq!(scancode, 17);
+        assert!(mods == glfw::Shift);
+      }
+      _ => fail!("expected a key press")
+    }
+    match events[1].event {
+      glfw::CursorPosEvent(x, y) => assert_eq!((x, y), (10.0, 20.5)),
+      _ => fail!("expected a cursor move")
+    }
+    match events[2].event {
+      glfw::SizeEvent(w, h) => assert_eq!((w, h), (800, 600)),
+      _ => fail!("expected a resize")
+    }
+  }
+
+  #[test]
+  fn contour_around_a_peak_is_closed() {
+    let grid = square_grid(3, ~[0.0, 0.0, 0.0,
+                                0.0, 10.0, 0.0,
+                                0.0, 0.0, 0.0]);
+    let lines = trace_contour_level(&grid, 5.0);
+    assert_eq!(lines.len(), 1);
+
+    let line = lines[0].as_slice();
+    assert_eq!(line.len(), 5);
+    assert!(near(line[0], line[4].x, line[4].y));
+    for p in line.iter() {
+      assert!((p.x - 1.0).abs() + (p.y - 1.0).abs() - 0.5 < 1e-4);
+    }
+  }
+
+  #[test]
+  fn saddle_splits_by_the_corner_average() {
+    // Corners (0, 0) and (1, 1) are high, (1, 0) and (0, 1) low; the average is 5
+    let grid = square_grid(2, ~[10.0, 0.0,
+                                0.0, 10.0]);
+
+    // Below the average the high corners join through the middle, cutting off (1, 0)
+    let lines = trace_contour_level(&grid, 4.0);
+    assert_eq!(lines.len(), 2);
+    assert!(lines.iter().any(|l| l.len() == 2 && l.iter().any(|&p| near(p, 0.6, 0.0))
+                                             && l.iter().any(|&p| near(p, 1.0, 0.4))));
+
+    // Above it the low corners join, cutting off the high corner (0, 0)
+    let lines = trace_contour_level(&grid, 6.0);
+    assert_eq!(lines.len(), 2);
+    assert!(lines.iter().any(|l| l.len() == 2 && l.iter().any(|&p| near(p, 0.4, 0.0))
+                                             && l.iter().any(|&p| near(p, 0.0, 0.4))));
+  }
+
+  #[test]
+  fn flat_grid_has_no_contours() {
+    let grid = square_grid(3, vec::from_elem(9, 2.0f32));
+    assert_eq!(trace_contour_level(&grid, 5.0).len(), 0);
+  }
+
+  #[test]
+  fn terrain_stats_summarize_the_grid() {
+    let grid = square_grid(2, ~[0.0, 2.0,
+                                4.0, 6.0]);
+    let stats = terrain_stats(&grid, 3.0);
+
+    assert_eq!(stats.cells, 4);
+    assert_eq!((stats.min, stats.max, stats.mean), (0.0, 6.0, 3.0));
+    assert!((stats.std_dev - 5.0f32.sqrt()).abs() < 1e-5);
+    assert_eq!(stats.below_sea_level, 0.5);
+
+    // The highest cell falls in the last bin rather than past it
+    assert_eq!(stats.histogram.iter().fold(0, |a, &b| a + b), 4);
+    assert_eq!(stats.histogram[stats.histogram.len() - 1], 1);
+
+    assert_eq!(stats.hypsometry[0], 1.0);
+    assert_eq!(stats.hypsometry[stats.hypsometry.len() - 1], 0.25);
+    assert!((stats.hypsometric_integral - 0.5).abs() < 1e-5);
+
+    assert_eq!(stats.slope_classes.iter().fold(0, |a, &b| a + b), 4);
+  }
+
+  #[test]
+  fn terrain_stats_of_flat_ground() {
+    let stats = terrain_stats(&square_grid(2, ~[1.0, 1.0, 1.0, 1.0]), 0.0);
+    assert_eq!(stats.std_dev, 0.0);
+    assert_eq!(stats.histogram[0], 4);
+    assert_eq!(stats.hypsometric_integral, 0.0);
+    assert_eq!(stats.below_sea_level, 0.0);
+    assert_eq!(stats.slope_classes[0], 4);
+  }
+
+  // A valley falling towards x = 0 with a pit in the middle
+  fn valley() -> HeightGrid {
+    let mut heights = ~[];
+    for x in range(0, 5) {
+      for y in range(0, 5) {
+        heights.push(x as f32 * 2.0 + (y as f32 - 2.0).abs());
+      }
+    }
+    heights[2 * 5 + 2] = -3.0;
+    square_grid(5, heights)
+  }
+
+  #[test]
+  fn pit_is_filled_to_its_spill_point() {
+    let grid = square_grid(3, ~[5.0, 5.0, 5.0,
+                                5.0, 1.0, 5.0,
+                                5.0, 5.0, 5.0]);
+    let (filled, order) = fill_depressions(&grid);
+
+    assert!((filled[4] - (5.0 + FILL_EPSILON)).abs() < 1e-6);
+    assert_eq!(order.len(), 9);
+    assert_eq!(order[8], 4);
+    assert!(d8_downstream(&grid, filled, 4).is_some());
+  }
+
+  #[test]
+  fn every_inner_cell_drains_after_filling() {
+    let grid = valley();
+    let (filled, _) = fill_depressions(&grid);
+    for x in range(1u, 4) {
+      for y in range(1u, 4) {
+        assert!(d8_downstream(&grid, filled, x * 5 + y).is_some());
+      }
+    }
+  }
+
+  #[test]
+  fn accumulation_is_conserved() {
+    let grid = valley();
+
+    let drainage = analyze_drainage(&grid, D8);
+    let at_outlets = range(0u, 25).filter(|&i| drainage.downstream[i].is_none())
+                                  .fold(0.0, |a, i| a + drainage.accumulation[i]);
+    assert_eq!(at_outlets, 25.0);
+
+    let drainage = analyze_drainage(&grid, DInfinity);
+    let at_outlets = range(0u, 25).filter(|&i| dinf_receivers(&grid, drainage.filled, i).len() == 0)
+                                  .fold(0.0, |a, i| a + drainage.accumulation[i]);
+    assert!((at_outlets - 25.0).abs() < 1e-3);
+  }
+
+  #[test]
+  fn each_basin_has_one_outlet() {
+    let grid = valley();
+    let drainage = analyze_drainage(&grid, D8);
+
+    let mut outlets = vec::from_elem(drainage.num_basins, 0u);
+    for i in range(0u, 25) {
+      if drainage.downstream[i].is_none() { outlets[drainage.basins[i]] += 1 }
+    }
+    assert!(outlets.iter().all(|&n| n == 1));
+  }
+
+  #[test]
+  fn dinf_shares_sum_to_one() {
+    let grid = valley();
+    let (filled, _) = fill_depressions(&grid);
+
+    for x in range(1u, 4) {
+      for y in range(1u, 4) {
+        let i = x * 5 + y;
+        let receivers = dinf_receivers(&grid, filled, i);
+        assert!(receivers.len() > 0);
+        assert!(receivers.iter().all(|&(n, share)| share >= 0.0 && share <= 1.0 && (share == 0.0 || filled[n] < filled[i])));
+        assert!((receivers.iter().fold(0.0, |a, &(_, share)| a + share) - 1.0).abs() < 1e-6);
+      }
+    }
+  }
+
+  #[test]
+  fn voronoi_regions_follow_the_nearest_seed() {
+    let grid = square_grid(4, vec::from_elem(16, 0.0f32));
+    let regions = voronoi_regions(&grid, [Vec2::new(0.0f32, 0.0), Vec2::new(3.0f32, 3.0)]);
+
+    assert_eq!(regions.count, 2);
+    assert_eq!(regions.labels[0 * 4 + 1], 0);
+    assert_eq!(regions.labels[3 * 4 + 2], 1);
+    assert_eq!(regions.labels[1 * 4 + 1], 0);
+    assert_eq!(regions.labels[2 * 4 + 2], 1);
+  }
+
+  #[test]
+  fn png_checksums_match_known_values() {
+    assert_eq!(crc32("IEND".as_bytes()), 0xae426082);
+
+    // zlib of "abc" in a single stored block
+    assert_eq!(zlib_stored("abc".as_bytes()),
+               ~[0x78u8, 0x01, 0x01, 0x03, 0x00, 0xfc, 0xff, 'a' as u8, 'b' as u8, 'c' as u8, 0x02, 0x4d, 0x01, 0x27]);
+  }
+
+  #[test]
+  fn flat_ground_is_visible_within_range() {
+    let grid = square_grid(7, vec::from_elem(49, 0.0f32));
+    let viewshed = compute_viewshed(&grid, (3, 3), 1.0, 2.5);
+
+    assert_eq!(viewshed.cells[3 * 7 + 3], Some(true));
+    assert_eq!(viewshed.cells[1 * 7 + 2], Some(true));
+    assert_eq!(viewshed.cells[0 * 7 + 0], None);
+    assert_eq!(viewshed.visible, viewshed.cells.iter().count(|&cell| cell.is_some()));
+  }
+
+  #[test]
+  fn wall_hides_the_ground_behind_it() {
+    let heights = vec::from_fn(49, |i| if i % 7 == 3 { 10.0f32 } else { 0.0 });
+    let grid = square_grid(7, heights);
+    let viewshed = compute_viewshed(&grid, (3, 0), 1.0, 10.0);
+
+    assert_eq!(viewshed.cells[3 * 7 + 2], Some(true));
+    assert_eq!(viewshed.cells[3 * 7 + 3], Some(true));
+    for x in range(0u, 7) {
+      for y in range(4u, 7) {
+        assert_eq!(viewshed.cells[x * 7 + y], Some(false));
+      }
+    }
+  }
+
+  // The center cell of each raster of a 5x5 grid, in 8 bits
+  fn center_rasters(height: |uint, uint| -> f32) -> ~[(&'static str, f32)] {
+    let mut heights = ~[];
+    for x in range(0u, 5) {
+      for y in range(0u, 5) { heights.push(height(x, y)) }
+    }
+    let grid = square_grid(5, heights);
+    terrain_rasters(&grid, 8).move_iter().map(|(name, values)| (name, values[2 * 5 + 2])).collect()
+  }
+
+  fn raster(rasters: &[(&'static str, f32)], name: &str) -> f32 {
+    let &(_, value) = rasters.iter().find(|&&(n, _)| n == name).unwrap();
+    value
+  }
+
+  #[test]
+  fn aspect_is_the_downslope_azimuth() {
+    // Mapped from 1 up, with 0 kept for flat ground
+    let expected = |degrees: f32| (1.0 + degrees / 360.0 * 254.0) / 255.0;
+
+    // Grid x runs south and grid y east
+    let north = center_rasters(|x, _| x as f32);
+    let east = center_rasters(|_, y| -(y as f32));
+    let south = center_rasters(|x, _| -(x as f32));
+    let west = center_rasters(|_, y| y as f32);
+
+    assert!((raster(north, "aspect") - expected(0.0)).abs() < 1e-4);
+    assert!((raster(east, "aspect") - expected(90.0)).abs() < 1e-4);
+    assert!((raster(south, "aspect") - expected(180.0)).abs() < 1e-4);
+    assert!((raster(west, "aspect") - expected(270.0)).abs() < 1e-4);
+  }
+
+  #[test]
+  fn flat_ground_rasters() {
+    let flat = center_rasters(|_, _| 3.0);
+
+    assert_eq!(raster(flat, "aspect"), 0.0);
+    assert_eq!(raster(flat, "slope"), 0.0);
+    assert_eq!(raster(flat, "curvature"), 0.5);
+    assert!((raster(flat, "hillshade") - LIGHTMAP_SUN_ALTITUDE.to_radians().sin()).abs() < 1e-4);
+  }
+
+  #[test]
+  fn curvature_is_positive_on_hilltops() {
+    let peak = center_rasters(|x, y| if x == 2 && y == 2 { 1.0 } else { 0.0 });
+    let pit = center_rasters(|x, y| if x == 2 && y == 2 { -1.0 } else { 0.0 });
+
+    assert!(raster(peak, "curvature") > 0.5);
+    assert!(raster(pit, "curvature") < 0.5);
+  }
+
+  #[test]
+  fn slope_of_a_45_degree_ramp() {
+    let ramp = center_rasters(|x, _| x as f32);
+    assert!((raster(ramp, "slope") - 0.5).abs() < 1e-4);
   }
 }
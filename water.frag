@@ -0,0 +1,91 @@
+#version 150
+
+#include "common.glsl"
+#include "fog.glsl"
+
+out vec4 out_color;
+
+uniform mat4 M; // Model
+
+uniform float sea_level;
+uniform float timer;
+
+// Terrain elevation, with texture rows following grid x like the lightmap
+uniform sampler2D height_map;
+
+// Terrain above the water seen in the mirror, and below it seen by the camera
+uniform bool reflections_enabled;
+uniform sampler2D reflection_map;
+uniform sampler2D refraction_map;
+
+// Tileable ripple normals, scrolled to distort the reflections
+uniform sampler2D normal_map;
+
+in Water {
+  vec3 eye;
+  vec3 position;
+  vec3 normal;
+  vec4 clip;
+} vs_out;
+
+const float shore_depth = 0.5;  // Foam fades out over this depth
+const float depth_falloff = 4.0; // Depth at which the water mostly takes the deep color
+
+const float ripple_scale = 0.15;    // Ripple tiles per grid unit
+const float ripple_speed = 0.0004;  // Ripple tiles per frame
+const float ripple_strength = 0.3;  // How far ripples tilt the wave normal
+const float distortion = 0.02;      // Screen space offset of the reflections
+const float water_reflectance = 0.02; // Fresnel reflectance looking straight down
+
+void main() {
+  vec4 foam = vec4(0.9, 0.95, 1.0, 1.0);
+
+  vec2 coord = (vs_out.position.yx + 0.5) / vec2(textureSize(height_map, 0));
+  float depth = max(vs_out.position.z - texture(height_map, coord).r, 0.0);
+
+  float murk = 1.0 - exp(-depth / depth_falloff);
+  float shore = 1.0 - smoothstep(0.0, shore_depth, depth);
+  vec4 color = mix(shallow_water, deep_water, murk);
+
+  // Two ripple layers scrolling in different directions, each in [-1, 1]
+  vec2 uv = vs_out.position.xy * ripple_scale;
+  vec2 ripple = texture(normal_map, uv + vec2(1.0, 0.3) * ripple_speed * timer).rg
+              + texture(normal_map, uv * 1.7 - vec2(0.4, 1.0) * ripple_speed * timer).rg - 1.0;
+  vec3 normal = normalize(vs_out.normal + vec3(ripple * ripple_strength, 0.0));
+
+  // Same lighting as the terrain, see test.frag
+  vec3 N = (V * -1 * vec4(normal, 0)).xyz;
+
+  vec3 L = normalize((V * vec4(sunlight.direction, 0.0)).xyz);
+  vec3 E = normalize(vs_out.eye);
+  vec3 R = normalize(-reflect(L, N));
+
+  vec3 light = sunlight.color * sunlight.intensity;
+  vec3 diffuse = max(-dot(N, L), 0.0) * light;
+  vec3 specular = pow(max(-dot(R, E), 0.0), 32.0) * light;
+
+  vec3 water = color.rgb * (diffuse + ambient);
+
+  if (!reflections_enabled) {
+    // Shallow water is clearer, so more of the sea floor shows through
+    float alpha = mix(0.35, 0.9, smoothstep(0.0, depth_falloff * 2.0, depth));
+    out_color = vec4(mix(water, foam.rgb * (diffuse + ambient), shore) + specular, alpha);
+    out_color.rgb = mix(out_color.rgb, fog_color, fog_amount(vs_out.position.z));
+    return;
+  }
+
+  // The targets are rendered with the same projection, so both line up with the screen
+  vec2 screen = vs_out.clip.xy / vs_out.clip.w * 0.5 + 0.5;
+  vec2 offset = ripple * distortion;
+  vec3 reflection = texture(reflection_map, clamp(screen + offset, 0.001, 0.999)).rgb;
+  vec3 refraction = texture(refraction_map, clamp(screen + offset * 0.5, 0.001, 0.999)).rgb;
+
+  // Schlick's approximation: grazing angles reflect, steep ones look through
+  float fresnel = water_reflectance + (1.0 - water_reflectance) * pow(1.0 - abs(dot(N, E)), 5.0);
+
+  vec3 below = mix(refraction, water, murk);
+  vec3 surface = mix(below, reflection, fresnel);
+
+  out_color = vec4(mix(surface, foam.rgb * (diffuse + ambient), shore) + specular, 1.0);
+  out_color.rgb = mix(out_color.rgb, fog_color, fog_amount(vs_out.position.z));
+}
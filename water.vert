@@ -0,0 +1,56 @@
+#version 330
+
+#include "common.glsl"
+
+// Flat grid over the terrain in grid coordinates, displaced by Gerstner waves
+layout (location = 0) in vec2 position;
+
+uniform mat4 M; // Model
+
+uniform float timer;
+uniform float sea_level;
+
+// Wave parameters, see WAVES. NUM_WAVES is defined by load_shader_file.
+uniform vec2 wave_directions[NUM_WAVES];
+uniform float wave_lengths[NUM_WAVES];
+uniform float wave_amplitudes[NUM_WAVES];
+uniform float wave_steepness[NUM_WAVES];
+uniform float wave_speeds[NUM_WAVES];
+
+out Water {
+  vec3 eye;
+  vec3 position; // Grid x, y and elevation
+  vec3 normal;   // With elevation as z like the terrain normals, but pointing up
+  vec4 clip;     // For looking up the reflection and refraction targets
+} vs_out;
+
+void main() {
+  vec3 p = vec3(position, sea_level);
+  vec3 n = vec3(0.0, 0.0, 1.0);
+
+  for (int i = 0; i < NUM_WAVES; ++i) {
+    vec2 d = normalize(wave_directions[i]);
+    float k = 2.0 * pi / wave_lengths[i];
+    float a = wave_amplitudes[i];
+
+    // Steepness is shared out between the waves so crests never loop over
+    float q = wave_steepness[i] / (k * a * float(NUM_WAVES));
+    float phase = k * (dot(d, position) - wave_speeds[i] * timer);
+
+    p.xy += q * a * d * cos(phase);
+    p.z  += a * sin(phase);
+
+    n.xy -= d * k * a * cos(phase);
+    n.z  -= q * k * a * sin(phase);
+  }
+
+  // Elevation is drawn towards -z, as in test.vert
+  vec4 pos = vec4(p.xy, -p.z, 1.0);
+
+  vs_out.position = p;
+  vs_out.normal = normalize(n);
+  vs_out.eye = (V * M * pos).xyz;
+
+  gl_Position = P * V * M * pos;
+  vs_out.clip = gl_Position;
+}
@@ -0,0 +1,12 @@
+#version 330
+
+#include "common.glsl"
+
+layout (location = 0) in vec3 position;
+
+uniform mat4 M; // Model
+
+void main() {
+  // Heights are flipped like in test.vert
+  gl_Position = P * V * M * vec4(position.xy, position.z * -1.0, 1.0);
+}